@@ -0,0 +1,78 @@
+use plain::Plain;
+
+/// Common part of the USB 2.0 and SuperSpeed hub descriptors, the port bitmaps that follow are not used
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HubDescriptor {
+    pub length: u8,
+    pub kind: u8,
+    pub ports: u8,
+    pub characteristics: u16,
+    /// Time from powering on a port until its power is good, in units of 2 ms
+    pub power_good: u8,
+    pub current: u8,
+}
+
+unsafe impl Plain for HubDescriptor {}
+
+impl HubDescriptor {
+    /// TT Think Time field of wHubCharacteristics, in the encoding used by the slot context
+    pub fn tt_think_time(&self) -> u8 {
+        ((self.characteristics >> 5) & 0b11) as u8
+    }
+}
+
+/// wPortStatus bits
+const PORT_STAT_CONNECTION: u16 = 1 << 0;
+const PORT_STAT_ENABLE: u16 = 1 << 1;
+const PORT_STAT_LOW_SPEED: u16 = 1 << 9;
+const PORT_STAT_HIGH_SPEED: u16 = 1 << 10;
+
+/// wPortChange bits
+const PORT_CHANGE_CONNECTION: u16 = 1 << 0;
+const PORT_CHANGE_RESET: u16 = 1 << 4;
+
+/// Result of a GET_STATUS request on a hub port
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PortStatus {
+    pub status: u16,
+    pub change: u16,
+}
+
+impl PortStatus {
+    pub fn from_bytes(data: &[u8]) -> Option<PortStatus> {
+        if data.len() < 4 {
+            return None;
+        }
+        Some(PortStatus {
+            status: data[0] as u16 | (data[1] as u16) << 8,
+            change: data[2] as u16 | (data[3] as u16) << 8,
+        })
+    }
+
+    pub fn connected(&self) -> bool {
+        self.status & PORT_STAT_CONNECTION != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.status & PORT_STAT_ENABLE != 0
+    }
+
+    /// Only valid for USB 2.0 hubs, on SuperSpeed hubs these bits hold the port power state
+    pub fn low_speed(&self) -> bool {
+        self.status & PORT_STAT_LOW_SPEED != 0
+    }
+
+    /// Only valid for USB 2.0 hubs, on SuperSpeed hubs these bits hold the port power state
+    pub fn high_speed(&self) -> bool {
+        self.status & PORT_STAT_HIGH_SPEED != 0
+    }
+
+    pub fn connection_changed(&self) -> bool {
+        self.change & PORT_CHANGE_CONNECTION != 0
+    }
+
+    pub fn reset_changed(&self) -> bool {
+        self.change & PORT_CHANGE_RESET != 0
+    }
+}
@@ -0,0 +1,183 @@
+use plain::Plain;
+use std::{mem, thread};
+use std::time::Duration;
+
+use usb;
+use xhci::{PortSpeed, Timeout, TransferKind, Xhci, XhciError, TIMEOUT_MS};
+
+pub use self::descriptor::{HubDescriptor, PortStatus};
+
+mod descriptor;
+
+/// bDeviceClass of hubs
+pub const HUB_CLASS: u8 = 0x09;
+
+/// Descriptor types of USB 2.0 and SuperSpeed hubs
+const HUB_DESCRIPTOR: u8 = 0x29;
+const SS_HUB_DESCRIPTOR: u8 = 0x2A;
+
+/// Hub class requests
+const REQ_GET_STATUS: u8 = 0x00;
+const REQ_CLEAR_FEATURE: u8 = 0x01;
+const REQ_SET_FEATURE: u8 = 0x03;
+const REQ_GET_DESCRIPTOR: u8 = 0x06;
+const REQ_SET_HUB_DEPTH: u8 = 0x0C;
+
+/// Request types for requests to the hub and to one of its ports
+const TYPE_HUB_IN: u8 = 0b1010_0000;
+const TYPE_HUB_OUT: u8 = 0b0010_0000;
+const TYPE_PORT_IN: u8 = 0b1010_0011;
+const TYPE_PORT_OUT: u8 = 0b0010_0011;
+
+/// Port feature selectors
+pub const PORT_RESET: u16 = 4;
+pub const PORT_POWER: u16 = 8;
+pub const C_PORT_CONNECTION: u16 = 16;
+pub const C_PORT_RESET: u16 = 20;
+
+/// Time a device needs to recover after its port has been reset, TRSTRCY
+const RESET_RECOVERY_MS: u64 = 10;
+
+fn class_setup(kind: u8, request: u8, value: u16, index: u16, length: u16) -> usb::Setup {
+    usb::Setup {
+        kind: kind,
+        request: request,
+        value: value,
+        index: index,
+        length: length,
+    }
+}
+
+/// A configured hub, which has to be enumerated through class requests before the devices on its downstream
+/// ports can be addressed
+pub struct UsbHub {
+    slot: u8,
+    speed: PortSpeed,
+    desc: HubDescriptor,
+}
+
+impl UsbHub {
+    /// Read the hub descriptor of a configured hub slot, describe the hub to the controller and power on its ports
+    pub fn new(hci: &mut Xhci, slot: u8) -> Result<UsbHub, XhciError> {
+        let speed = hci.slot_speed(slot)?;
+        let super_speed = speed == PortSpeed::SuperSpeed || speed == PortSpeed::SuperSpeedPlus;
+
+        let mut desc = HubDescriptor::default();
+        {
+            let kind = if super_speed { SS_HUB_DESCRIPTOR } else { HUB_DESCRIPTOR };
+            let mut data = vec![0; mem::size_of::<HubDescriptor>()];
+            let setup = class_setup(TYPE_HUB_IN, REQ_GET_DESCRIPTOR, (kind as u16) << 8, 0, data.len() as u16);
+            let count = hci.control_transfer(slot, setup, TransferKind::In, Some(&mut data[..]))?;
+            desc.copy_from_bytes(&data[..count]).or(Err(XhciError::InvalidState("short hub descriptor")))?;
+        }
+        debug!("    - Hub {}: {:?}", slot, desc);
+
+        if super_speed {
+            // The hub uses its depth to find its own port number in route strings
+            let depth = hci.hub_depth(slot)?;
+            hci.control_transfer(slot, class_setup(TYPE_HUB_OUT, REQ_SET_HUB_DEPTH, depth as u16, 0, 0), TransferKind::NoData, None)?;
+        }
+
+        // Only the single TT of the default interface setting is used, even by multi TT hubs
+        hci.configure_hub(slot, desc.ports, false, desc.tt_think_time())?;
+
+        let hub = UsbHub {
+            slot: slot,
+            speed: speed,
+            desc: desc,
+        };
+
+        for port in 1..hub.ports() as u16 + 1 {
+            hub.set_port_feature(hci, port as u8, PORT_POWER)?;
+        }
+        thread::sleep(Duration::from_millis(desc.power_good as u64 * 2));
+
+        Ok(hub)
+    }
+
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn ports(&self) -> u8 {
+        self.desc.ports
+    }
+
+    pub fn set_port_feature(&self, hci: &mut Xhci, port: u8, feature: u16) -> Result<(), XhciError> {
+        hci.control_transfer(self.slot, class_setup(TYPE_PORT_OUT, REQ_SET_FEATURE, feature, port as u16, 0), TransferKind::NoData, None)?;
+        Ok(())
+    }
+
+    pub fn clear_port_feature(&self, hci: &mut Xhci, port: u8, feature: u16) -> Result<(), XhciError> {
+        hci.control_transfer(self.slot, class_setup(TYPE_PORT_OUT, REQ_CLEAR_FEATURE, feature, port as u16, 0), TransferKind::NoData, None)?;
+        Ok(())
+    }
+
+    pub fn port_status(&self, hci: &mut Xhci, port: u8) -> Result<PortStatus, XhciError> {
+        let mut data = [0; 4];
+        let count = hci.control_transfer(self.slot, class_setup(TYPE_PORT_IN, REQ_GET_STATUS, 0, port as u16, 4), TransferKind::In, Some(&mut data[..]))?;
+        PortStatus::from_bytes(&data[..count]).ok_or(XhciError::InvalidState("short port status"))
+    }
+
+    /// Reset a downstream port, starting at 1, which enables it once the reset completes
+    pub fn reset_port(&self, hci: &mut Xhci, port: u8) -> Result<PortStatus, XhciError> {
+        self.set_port_feature(hci, port, PORT_RESET)?;
+
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        let status = loop {
+            let status = self.port_status(hci, port)?;
+            if status.reset_changed() {
+                break status;
+            }
+            timeout.check()?;
+        };
+        self.clear_port_feature(hci, port, C_PORT_RESET)?;
+
+        thread::sleep(Duration::from_millis(RESET_RECOVERY_MS));
+        Ok(status)
+    }
+
+    /// Speed of the device on a downstream port, SuperSpeed hubs only have SuperSpeed ports
+    fn port_speed(&self, status: PortStatus) -> PortSpeed {
+        match self.speed {
+            PortSpeed::SuperSpeed | PortSpeed::SuperSpeedPlus => PortSpeed::SuperSpeed,
+            _ => if status.low_speed() {
+                PortSpeed::LowSpeed
+            } else if status.high_speed() {
+                PortSpeed::HighSpeed
+            } else {
+                PortSpeed::FullSpeed
+            }
+        }
+    }
+
+    /// Reset and enumerate the devices connected to the downstream ports, returning (port, slot) pairs for
+    /// the devices found. A port that fails to enumerate is logged and skipped.
+    pub fn enumerate_ports(&self, hci: &mut Xhci) -> Result<Vec<(u8, u8)>, XhciError> {
+        let mut devices = Vec::new();
+        for port in 1..self.ports() as u16 + 1 {
+            let port = port as u8;
+            let status = self.port_status(hci, port)?;
+            debug!("    - Hub {} port {}: {:?}", self.slot, port, status);
+
+            if status.connection_changed() {
+                self.clear_port_feature(hci, port, C_PORT_CONNECTION)?;
+            }
+            if ! status.connected() {
+                continue;
+            }
+
+            let status = self.reset_port(hci, port)?;
+            if ! status.enabled() {
+                warn!("    - Hub {} port {}: not enabled after reset", self.slot, port);
+                continue;
+            }
+
+            match hci.attach_hub_device(self.slot, port, self.port_speed(status)) {
+                Ok(slot) => devices.push((port, slot)),
+                Err(err) => error!("    - Hub {} port {}: failed to enumerate: {}", self.slot, port, err),
+            }
+        }
+        Ok(devices)
+    }
+}
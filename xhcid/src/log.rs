@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Debug as usize);
+
+/// Set the most verbose level that is printed
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        if $crate::log::enabled($level) {
+            println!($($arg)*);
+        }
+    })
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::LogLevel::Trace, $($arg)*))
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::LogLevel::Debug, $($arg)*))
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::LogLevel::Info, $($arg)*))
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::LogLevel::Warn, $($arg)*))
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::LogLevel::Error, $($arg)*))
+}
@@ -1,3 +1,5 @@
+#![feature(try_from)]
+
 #[macro_use]
 extern crate bitflags;
 extern crate event;
@@ -17,9 +19,15 @@ use syscall::scheme::SchemeMut;
 
 use xhci::Xhci;
 
+#[macro_use]
+mod log;
+mod hub;
 mod usb;
 mod xhci;
 
+/// Size of the mapping of the controller's BAR
+const BAR_SIZE: usize = 65536;
+
 fn main() {
     let mut args = env::args().skip(1);
 
@@ -41,9 +49,9 @@ fn main() {
 
         let mut irq_file = File::open(format!("irq:{}", irq)).expect("xhcid: failed to open IRQ file");
 
-        let address = unsafe { syscall::physmap(bar, 65536, syscall::MAP_WRITE).expect("xhcid: failed to map address") };
+        let address = unsafe { syscall::physmap(bar, BAR_SIZE, syscall::MAP_WRITE).expect("xhcid: failed to map address") };
         {
-            let hci = Arc::new(RefCell::new(Xhci::new(address).expect("xhcid: failed to allocate device")));
+            let hci = Arc::new(RefCell::new(Xhci::new(address, BAR_SIZE).expect("xhcid: failed to allocate device")));
 
             hci.borrow_mut().probe().expect("xhcid: failed to probe");
 
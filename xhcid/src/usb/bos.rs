@@ -0,0 +1,218 @@
+use plain::Plain;
+use std::cmp;
+
+use super::DescriptorKind;
+
+/// Header of the BOS descriptor, followed by `capabilities` device capability descriptors
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BosHeader {
+    pub length: u8,
+    pub kind: u8,
+    pub total_length: u16,
+    pub capabilities: u8,
+}
+
+unsafe impl Plain for BosHeader {}
+
+/// bDevCapabilityType of device capability descriptors
+const CAP_USB20_EXTENSION: u8 = 0x02;
+const CAP_SUPERSPEED: u8 = 0x03;
+const CAP_CONTAINER_ID: u8 = 0x04;
+const CAP_PLATFORM: u8 = 0x05;
+const CAP_SUPERSPEED_PLUS: u8 = 0x0A;
+
+/// bmAttributes bits of the USB 2.0 Extension capability
+const USB20_EXT_LPM: u32 = 1 << 1;
+const USB20_EXT_BASELINE_BESL_VALID: u32 = 1 << 3;
+const USB20_EXT_BASELINE_BESL_SHIFT: u32 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BosCapability {
+    Usb20Extension {
+        attributes: u32,
+    },
+    SuperSpeed {
+        attributes: u8,
+        speeds_supported: u16,
+        functionality_support: u8,
+        /// bU1DevExitLat, in microseconds
+        u1_dev_exit_lat: u8,
+        /// wU2DevExitLat, in microseconds
+        u2_dev_exit_lat: u16,
+    },
+    SuperSpeedPlus {
+        attributes: u32,
+        functionality_support: u16,
+        sublink_speed_attributes: Vec<u32>,
+    },
+    ContainerId {
+        uuid: [u8; 16],
+    },
+    PlatformCapability {
+        uuid: [u8; 16],
+        data: Vec<u8>,
+    },
+    /// Any other capability, including its header
+    Unknown(Vec<u8>),
+}
+
+fn read_u16(buf: &[u8], i: usize) -> u16 {
+    buf[i] as u16 | (buf[i + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    read_u16(buf, i) as u32 | (read_u16(buf, i + 2) as u32) << 16
+}
+
+fn read_uuid(buf: &[u8]) -> [u8; 16] {
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&buf[4..20]);
+    uuid
+}
+
+impl BosCapability {
+    /// Parse a single device capability descriptor, `buf` being exactly its bLength bytes
+    fn parse(buf: &[u8]) -> BosCapability {
+        let kind = if buf.len() >= 3 { buf[2] } else { 0 };
+        match kind {
+            CAP_USB20_EXTENSION if buf.len() >= 7 => BosCapability::Usb20Extension {
+                attributes: read_u32(buf, 3),
+            },
+            CAP_SUPERSPEED if buf.len() >= 10 => BosCapability::SuperSpeed {
+                attributes: buf[3],
+                speeds_supported: read_u16(buf, 4),
+                functionality_support: buf[6],
+                u1_dev_exit_lat: buf[7],
+                u2_dev_exit_lat: read_u16(buf, 8),
+            },
+            CAP_SUPERSPEED_PLUS if buf.len() >= 12 => {
+                let attributes = read_u32(buf, 4);
+                // Sublink Speed Attribute Count is the number of attributes minus one
+                let count = (attributes & 0x1F) as usize + 1;
+                let sublink_speed_attributes = (0..count)
+                    .map(|i| 12 + i * 4)
+                    .take_while(|&i| i + 4 <= buf.len())
+                    .map(|i| read_u32(buf, i))
+                    .collect();
+                BosCapability::SuperSpeedPlus {
+                    attributes: attributes,
+                    functionality_support: read_u16(buf, 8),
+                    sublink_speed_attributes: sublink_speed_attributes,
+                }
+            },
+            CAP_CONTAINER_ID if buf.len() >= 20 => BosCapability::ContainerId {
+                uuid: read_uuid(buf),
+            },
+            CAP_PLATFORM if buf.len() >= 20 => BosCapability::PlatformCapability {
+                uuid: read_uuid(buf),
+                data: buf[20..].to_vec(),
+            },
+            _ => BosCapability::Unknown(buf.to_vec()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BosDescriptor {
+    pub capabilities: Vec<BosCapability>,
+}
+
+impl BosDescriptor {
+    /// Parse a full BOS descriptor, returning `None` if the header is missing
+    pub fn parse(buf: &[u8]) -> Option<BosDescriptor> {
+        let mut header = BosHeader::default();
+        header.copy_from_bytes(buf).ok()?;
+        if header.kind != DescriptorKind::Bos as u8 {
+            return None;
+        }
+
+        let mut capabilities = Vec::new();
+        let buf = &buf[..cmp::min(header.total_length as usize, buf.len())];
+        let mut i = header.length as usize;
+        while i + 2 <= buf.len() && capabilities.len() < header.capabilities as usize {
+            let length = buf[i] as usize;
+            if length < 3 {
+                break;
+            }
+            let end = cmp::min(i + length, buf.len());
+            if buf[i + 1] == DescriptorKind::DeviceCapability as u8 {
+                capabilities.push(BosCapability::parse(&buf[i..end]));
+            }
+            i += length;
+        }
+
+        Some(BosDescriptor {
+            capabilities: capabilities,
+        })
+    }
+
+    /// Exit latencies of the SuperSpeed capability, as (bU1DevExitLat, wU2DevExitLat)
+    pub fn u1_u2_exit_latency(&self) -> Option<(u8, u16)> {
+        self.capabilities.iter().filter_map(|cap| match *cap {
+            BosCapability::SuperSpeed { u1_dev_exit_lat, u2_dev_exit_lat, .. } => Some((u1_dev_exit_lat, u2_dev_exit_lat)),
+            _ => None,
+        }).next()
+    }
+
+    /// Whether the USB 2.0 Extension capability reports support for Link Power Management
+    pub fn supports_lpm(&self) -> bool {
+        self.usb20_attributes().map(|attributes| attributes & USB20_EXT_LPM != 0).unwrap_or(false)
+    }
+
+    /// Recommended Baseline BESL of the USB 2.0 Extension capability, if the device gives one
+    pub fn baseline_besl(&self) -> Option<u8> {
+        let attributes = self.usb20_attributes()?;
+        if attributes & USB20_EXT_BASELINE_BESL_VALID != 0 {
+            Some(((attributes >> USB20_EXT_BASELINE_BESL_SHIFT) & 0xF) as u8)
+        } else {
+            None
+        }
+    }
+
+    fn usb20_attributes(&self) -> Option<u32> {
+        self.capabilities.iter().filter_map(|cap| match *cap {
+            BosCapability::Usb20Extension { attributes } => Some(attributes),
+            _ => None,
+        }).next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BosCapability, BosDescriptor};
+
+    const BOS_BYTES: [u8; 22] = [
+        // BOS header with 2 capabilities
+        0x05, 0x0f, 0x16, 0x00, 0x02,
+        // USB 2.0 extension, LPM supported
+        0x07, 0x10, 0x02, 0x02, 0x00, 0x00, 0x00,
+        // SuperSpeed, U1 exit latency 10 us, U2 exit latency 2047 us
+        0x0a, 0x10, 0x03, 0x00, 0x0e, 0x00, 0x01, 0x0a, 0xff, 0x07,
+    ];
+
+    #[test]
+    fn parse_bos() {
+        let bos = BosDescriptor::parse(&BOS_BYTES).unwrap();
+        assert_eq!(bos.capabilities.len(), 2);
+        assert_eq!(bos.capabilities[0], BosCapability::Usb20Extension { attributes: 2 });
+        assert_eq!(bos.capabilities[1], BosCapability::SuperSpeed {
+            attributes: 0,
+            speeds_supported: 0x0e,
+            functionality_support: 1,
+            u1_dev_exit_lat: 10,
+            u2_dev_exit_lat: 2047,
+        });
+        assert_eq!(bos.u1_u2_exit_latency(), Some((10, 2047)));
+        assert!(bos.supports_lpm());
+        assert_eq!(bos.baseline_besl(), None);
+    }
+
+    #[test]
+    fn parse_bos_truncated() {
+        assert_eq!(BosDescriptor::parse(&BOS_BYTES[..3]), None);
+        let bos = BosDescriptor::parse(&BOS_BYTES[..12]).unwrap();
+        assert_eq!(bos.capabilities.len(), 1);
+        assert_eq!(bos.u1_u2_exit_latency(), None);
+    }
+}
@@ -1,3 +1,8 @@
+use plain::Plain;
+use std::cmp;
+
+use super::{DescriptorKind, EndpointDescriptor, InterfaceDescriptor, SuperSpeedCompanionDescriptor};
+
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct ConfigDescriptor {
@@ -10,3 +15,133 @@ pub struct ConfigDescriptor {
     pub attributes: u8,
     pub max_power: u8,
 }
+
+unsafe impl Plain for ConfigDescriptor {}
+
+/// Find the endpoint descriptors of alternate setting 0 of the first interface in a full configuration descriptor,
+/// each with the SuperSpeed Endpoint Companion descriptor that follows it on USB 3.x devices
+pub fn parse_endpoints(buf: &[u8]) -> Vec<(EndpointDescriptor, Option<SuperSpeedCompanionDescriptor>)> {
+    let mut endpoints = Vec::new();
+    let mut in_interface = false;
+    let mut after_endpoint = false;
+
+    let mut i = 0;
+    while i + 2 <= buf.len() {
+        let length = buf[i] as usize;
+        if length < 2 {
+            break;
+        }
+        let kind = buf[i + 1];
+        let end = cmp::min(i + length, buf.len());
+
+        let follows_endpoint = after_endpoint;
+        after_endpoint = false;
+        if kind == DescriptorKind::Interface as u8 {
+            // The next interface, or another alternate setting of this one
+            if in_interface {
+                break;
+            }
+            let mut desc = InterfaceDescriptor::default();
+            in_interface = desc.copy_from_bytes(&buf[i..end]).is_ok() && desc.alternate_setting == 0;
+        } else if kind == DescriptorKind::Endpoint as u8 && in_interface {
+            let mut desc = EndpointDescriptor::default();
+            if desc.copy_from_bytes(&buf[i..end]).is_ok() {
+                endpoints.push((desc, None));
+                after_endpoint = true;
+            }
+        } else if kind == DescriptorKind::SuperSpeedCompanion as u8 && follows_endpoint {
+            // The companion descriptor must immediately follow its endpoint descriptor
+            let mut desc = SuperSpeedCompanionDescriptor::default();
+            if desc.copy_from_bytes(&buf[i..end]).is_ok() {
+                if let Some(endpoint) = endpoints.last_mut() {
+                    endpoint.1 = Some(desc);
+                }
+            }
+        }
+
+        i += length;
+    }
+
+    endpoints
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_endpoints;
+
+    const CONFIG_BYTES: [u8; 50] = [
+        // Configuration
+        0x09, 0x02, 0x32, 0x00, 0x02, 0x01, 0x00, 0xa0, 0x32,
+        // Interface 0
+        0x09, 0x04, 0x00, 0x00, 0x02, 0x03, 0x01, 0x01, 0x00,
+        // HID
+        0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3f, 0x00,
+        // Endpoints of interface 0
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a,
+        0x07, 0x05, 0x02, 0x02, 0x00, 0x02, 0x00,
+        // Interface 1
+        0x09, 0x04, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn parse_first_interface() {
+        let endpoints = parse_endpoints(&CONFIG_BYTES);
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0].0.address, 0x81);
+        assert_eq!(endpoints[0].0.attributes, 0x03);
+        assert_eq!({ endpoints[0].0.max_packet_size }, 8);
+        assert_eq!(endpoints[0].0.interval, 10);
+        assert!(endpoints[0].1.is_none());
+        assert_eq!(endpoints[1].0.address, 0x02);
+        assert_eq!({ endpoints[1].0.max_packet_size }, 512);
+        assert!(endpoints[1].1.is_none());
+    }
+
+    #[test]
+    fn parse_alternate_setting_0() {
+        let bytes = [
+            // Configuration
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // Interface 0, alternate setting 0 without endpoints
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00,
+            // Interface 0, alternate setting 1 with an isochronous endpoint
+            0x09, 0x04, 0x00, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00,
+            0x07, 0x05, 0x81, 0x05, 0x00, 0x02, 0x01,
+        ];
+        assert_eq!(parse_endpoints(&bytes).len(), 0);
+    }
+
+    #[test]
+    fn parse_superspeed_companions() {
+        let bytes = [
+            // Configuration
+            0x09, 0x02, 0x2c, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // Interface 0
+            0x09, 0x04, 0x00, 0x00, 0x02, 0x01, 0x02, 0x00, 0x00,
+            // Isochronous IN endpoint with 4 packet bursts, 2 bursts per interval and 6144 bytes per interval
+            0x07, 0x05, 0x81, 0x05, 0x00, 0x04, 0x01,
+            0x06, 0x30, 0x03, 0x01, 0x00, 0x18,
+            // Bulk OUT endpoint with 16 packet bursts
+            0x07, 0x05, 0x02, 0x02, 0x00, 0x04, 0x00,
+            0x06, 0x30, 0x0f, 0x00, 0x00, 0x00,
+        ];
+        let endpoints = parse_endpoints(&bytes);
+        assert_eq!(endpoints.len(), 2);
+
+        let isoch = endpoints[0].1.unwrap();
+        assert_eq!(isoch.max_burst, 3);
+        assert_eq!(isoch.mult(), 1);
+        assert_eq!({ isoch.bytes_per_interval }, 6144);
+
+        let bulk = endpoints[1].1.unwrap();
+        assert_eq!(bulk.max_burst, 15);
+        assert_eq!({ bulk.bytes_per_interval }, 0);
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert_eq!(parse_endpoints(&CONFIG_BYTES[..30]).len(), 0);
+        assert_eq!(parse_endpoints(&[]).len(), 0);
+        assert_eq!(parse_endpoints(&[0x00, 0x02]).len(), 0);
+    }
+}
@@ -1,3 +1,5 @@
+use plain::Plain;
+
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct DeviceDescriptor {
@@ -16,3 +18,5 @@ pub struct DeviceDescriptor {
     pub serial_str: u8,
     pub configurations: u8,
 }
+
+unsafe impl Plain for DeviceDescriptor {}
@@ -12,3 +12,62 @@ pub struct EndpointDescriptor {
 }
 
 unsafe impl Plain for EndpointDescriptor {}
+
+/// SuperSpeed Endpoint Companion descriptor, which follows each endpoint descriptor of a USB 3.x device
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SuperSpeedCompanionDescriptor {
+    pub length: u8,
+    pub kind: u8,
+    /// Packets the endpoint can send or receive in a burst, minus one
+    pub max_burst: u8,
+    pub attributes: u8,
+    /// Bytes moved per service interval by periodic endpoints
+    pub bytes_per_interval: u16,
+}
+
+unsafe impl Plain for SuperSpeedCompanionDescriptor {}
+
+impl SuperSpeedCompanionDescriptor {
+    /// Bursts per service interval of isochronous endpoints, minus one
+    pub fn mult(&self) -> u8 {
+        self.attributes & 0b11
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointKind {
+    Control,
+    Isoch,
+    Bulk,
+    Interrupt,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 == 0x80
+    }
+
+    pub fn kind(&self) -> EndpointKind {
+        match self.attributes & 0b11 {
+            0 => EndpointKind::Control,
+            1 => EndpointKind::Isoch,
+            2 => EndpointKind::Bulk,
+            _ => EndpointKind::Interrupt,
+        }
+    }
+
+    /// Max packet size without the high-bandwidth transactions bits
+    pub fn packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+
+    /// Additional transactions per microframe of high speed periodic endpoints
+    pub fn transactions(&self) -> u8 {
+        ((self.max_packet_size >> 11) & 0b11) as u8
+    }
+}
@@ -1,8 +1,10 @@
-pub use self::config::ConfigDescriptor;
+pub use self::bos::{BosCapability, BosDescriptor, BosHeader};
+pub use self::config::{parse_endpoints, ConfigDescriptor};
 pub use self::device::DeviceDescriptor;
-pub use self::endpoint::EndpointDescriptor;
+pub use self::endpoint::{EndpointDescriptor, EndpointKind, SuperSpeedCompanionDescriptor};
 pub use self::interface::InterfaceDescriptor;
-pub use self::setup::Setup;
+pub use self::setup::{Setup, FEATURE_DEVICE_REMOTE_WAKEUP, FEATURE_FUNCTION_SUSPEND, FEATURE_U1_ENABLE, FEATURE_U2_ENABLE,
+                      FUNCTION_SUSPEND_REMOTE_WAKE};
 
 #[repr(u8)]
 pub enum DescriptorKind {
@@ -16,8 +18,12 @@ pub enum DescriptorKind {
     OtherSpeedConfiguration,
     InterfacePower,
     OnTheGo,
+    Bos = 15,
+    DeviceCapability = 16,
+    SuperSpeedCompanion = 48,
 }
 
+mod bos;
 mod config;
 mod device;
 mod endpoint;
@@ -1,5 +1,14 @@
 use super::DescriptorKind;
 
+/// Feature selectors for SET_FEATURE and CLEAR_FEATURE
+pub const FEATURE_FUNCTION_SUSPEND: u16 = 0;
+pub const FEATURE_DEVICE_REMOTE_WAKEUP: u16 = 1;
+pub const FEATURE_U1_ENABLE: u16 = 48;
+pub const FEATURE_U2_ENABLE: u16 = 49;
+
+/// Suspend option of FUNCTION_SUSPEND enabling function remote wake, in the upper byte of wIndex
+pub const FUNCTION_SUSPEND_REMOTE_WAKE: u8 = 1 << 1;
+
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Setup {
@@ -41,6 +50,17 @@ impl Setup {
         }
     }
 
+    /// SET_FEATURE addressed to an interface, `options` being the upper byte of wIndex
+    pub fn set_interface_feature(feature: u16, interface: u8, options: u8) -> Self {
+        Self {
+            kind: 0b0000_0001,
+            request: 0x03,
+            value: feature,
+            index: ((options as u16) << 8) | interface as u16,
+            length: 0,
+        }
+    }
+
     pub fn set_address(address: u16) -> Self {
         Self {
             kind: 0b0000_0000,
@@ -81,6 +101,17 @@ impl Setup {
         }
     }
 
+    /// SET_SEL, followed by 6 bytes of U1 and U2 system exit latencies
+    pub fn set_sel() -> Self {
+        Self {
+            kind: 0b0000_0000,
+            request: 0x30,
+            value: 0,
+            index: 0,
+            length: 6,
+        }
+    }
+
     pub fn set_configuration(value: u16) -> Self {
         Self {
             kind: 0b0000_0000,
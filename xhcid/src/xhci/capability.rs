@@ -1,4 +1,38 @@
-use syscall::io::Mmio;
+use syscall::io::{Io, Mmio};
+
+use super::error::XhciError;
+use super::timeout::Timeout;
+use super::{validate_region, TIMEOUT_MS};
+
+/// Extended capability ID of USB Legacy Support
+pub const CAP_ID_LEGACY: u8 = 1;
+/// Extended capability ID of Supported Protocol
+pub const CAP_ID_PROTOCOL: u8 = 2;
+
+/// Decoded fields of HCCPARAMS1
+#[derive(Clone, Copy, Debug)]
+pub struct HccParams1 {
+    /// 64-bit addressing capability
+    pub ac64: bool,
+    /// Bandwidth negotiation capability
+    pub bnc: bool,
+    /// Context size, contexts are 64 bytes instead of 32 if set
+    pub csz: bool,
+    /// Port power control
+    pub ppc: bool,
+    /// Port indicators
+    pub pind: bool,
+    /// Light host controller reset capability
+    pub lhrc: bool,
+    /// Latency tolerance messaging capability
+    pub ltc: bool,
+    /// No secondary stream ID support
+    pub nss: bool,
+    /// Maximum primary stream array size, as an exponent
+    pub max_psa_size: u8,
+    /// Extended capabilities pointer, in dwords from the MMIO base
+    pub xecp: u16,
+}
 
 #[repr(packed)]
 pub struct CapabilityRegs {
@@ -13,3 +47,219 @@ pub struct CapabilityRegs {
     pub rts_offset: Mmio<u32>,
     pub hcc_params2: Mmio<u32>
 }
+
+impl CapabilityRegs {
+    /// HCIVERSION, the BCD version of the xHCI specification the controller implements, such as 0x0110 for 1.1
+    pub fn version(&self) -> u16 {
+        self.hci_ver.read()
+    }
+
+    pub fn hccparams1(&self) -> HccParams1 {
+        let hcc_params1 = self.hcc_params1.read();
+        HccParams1 {
+            ac64: hcc_params1 & (1 << 0) != 0,
+            bnc: hcc_params1 & (1 << 1) != 0,
+            csz: hcc_params1 & (1 << 2) != 0,
+            ppc: hcc_params1 & (1 << 3) != 0,
+            pind: hcc_params1 & (1 << 4) != 0,
+            lhrc: hcc_params1 & (1 << 5) != 0,
+            ltc: hcc_params1 & (1 << 6) != 0,
+            nss: hcc_params1 & (1 << 7) != 0,
+            max_psa_size: ((hcc_params1 >> 12) & 0xF) as u8,
+            xecp: (hcc_params1 >> 16) as u16,
+        }
+    }
+
+    /// MaxIntrs, the number of interrupters the controller implements
+    pub fn max_interrupters(&self) -> u16 {
+        ((self.hcs_params1.read() >> 8) & 0x7FF) as u16
+    }
+
+    /// Most event ring segment table entries the controller supports, from the ERST Max exponent
+    ///
+    /// The exponent goes up to 15, so this does not fit in a `u8`.
+    pub fn erst_max(&self) -> u16 {
+        1 << ((self.hcs_params2.read() >> 4) & 0xF)
+    }
+
+    /// Worst case U1 exit latency of the root hub ports, in microseconds
+    pub fn u1_exit_latency(&self) -> u8 {
+        self.hcs_params3.read() as u8
+    }
+
+    /// Worst case U2 exit latency of the root hub ports, in microseconds, a 16 bit field
+    pub fn u2_exit_latency(&self) -> u16 {
+        (self.hcs_params3.read() >> 16) as u16
+    }
+
+    /// Number of scratchpad buffers the controller requires, split across two fields of HCSPARAMS2
+    pub fn max_scratchpad_bufs(&self) -> u32 {
+        let hcs_params2 = self.hcs_params2.read();
+        let hi = (hcs_params2 >> 21) & 0x1F;
+        let lo = (hcs_params2 >> 27) & 0x1F;
+        (hi << 5) | lo
+    }
+}
+
+/// Iterator over the extended capability list, yielding the ID and the offset from the MMIO base of each entry
+pub struct ExtendedCaps {
+    base: usize,
+    /// Size of the mapping at `base`, the walk stops at a capability outside of it
+    size: usize,
+    offset: usize,
+}
+
+impl Iterator for ExtendedCaps {
+    type Item = (u8, usize);
+
+    fn next(&mut self) -> Option<(u8, usize)> {
+        if self.offset == 0 {
+            return None;
+        }
+
+        let offset = self.offset;
+        if validate_region(self.base + offset, 4, self.base, self.size).is_err() {
+            self.offset = 0;
+            return None;
+        }
+        let header = unsafe { &*((self.base + offset) as *const Mmio<u32>) }.read();
+
+        // The next pointer is in dwords relative to this capability, zero ends the list
+        let next = ((header >> 8) & 0xFF) as usize;
+        self.offset = if next == 0 { 0 } else { offset + (next << 2) };
+
+        Some(((header & 0xFF) as u8, offset))
+    }
+}
+
+pub fn walk_extended_caps(base: usize, size: usize, hccparams1: u32) -> ExtendedCaps {
+    ExtendedCaps {
+        base: base,
+        size: size,
+        offset: ((hccparams1 >> 16) as usize) << 2,
+    }
+}
+
+/// A Supported Protocol capability, describing the USB version of a range of root hub ports
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProtocolCapability {
+    /// BCD major and minor revision, such as 3 and 0x10 for USB 3.1
+    pub major: u8,
+    pub minor: u8,
+    /// First port of the range, starting at 1
+    pub port_offset: u8,
+    pub port_count: u8,
+    /// Protocol Defined field, whose bits depend on the major revision
+    pub protocol_defined: u16,
+    /// Number of Protocol Speed ID dwords that follow, 0 if the default speed IDs are used
+    pub psic: u8,
+}
+
+/// L1 Capability bit of the Protocol Defined field of a USB 2.0 protocol, set if software can put ports into L1
+const USB2_L1C: u16 = 1 << 0;
+
+impl ProtocolCapability {
+    /// Decode the first dword of the capability and the dword after the name string
+    pub fn from_dwords(header: u32, ports: u32) -> ProtocolCapability {
+        ProtocolCapability {
+            major: (header >> 24) as u8,
+            minor: (header >> 16) as u8,
+            port_offset: ports as u8,
+            port_count: (ports >> 8) as u8,
+            protocol_defined: ((ports >> 16) & 0xFFF) as u16,
+            psic: (ports >> 28) as u8,
+        }
+    }
+
+    /// Whether a root hub port, starting at 1, is part of this range
+    pub fn contains(&self, port: u8) -> bool {
+        port >= self.port_offset && (port as u16) < self.port_offset as u16 + self.port_count as u16
+    }
+
+    /// Whether the ports of this range are USB 2.0 ports that support the L1 link power state
+    pub fn supports_l1(&self) -> bool {
+        self.major == 2 && self.protocol_defined & USB2_L1C != 0
+    }
+}
+
+pub fn parse_protocol_capabilities(base: usize, size: usize, hccparams1: u32) -> Vec<ProtocolCapability> {
+    walk_extended_caps(base, size, hccparams1).filter(|&(id, _)| id == CAP_ID_PROTOCOL).filter_map(|(_, offset)| {
+        validate_region(base + offset + 8, 4, base, size).ok()?;
+        let header = unsafe { &*((base + offset) as *const Mmio<u32>) }.read();
+        let ports = unsafe { &*((base + offset + 8) as *const Mmio<u32>) }.read();
+        Some(ProtocolCapability::from_dwords(header, ports))
+    }).collect()
+}
+
+/// Companion of a root hub port, starting at 1, which is the port at the same position in the first range of the
+/// other USB major version
+///
+/// This is how controllers usually lay out their ports, but the spec does not require it.
+pub fn companion_port(protocols: &[ProtocolCapability], port: u8) -> Option<u8> {
+    let own = protocols.iter().find(|protocol| protocol.contains(port))?;
+    let position = port - own.port_offset;
+    let other = protocols.iter().find(|protocol| (protocol.major >= 3) != (own.major >= 3))?;
+    if position < other.port_count {
+        Some(other.port_offset + position)
+    } else {
+        None
+    }
+}
+
+/// Take ownership of the controller from the BIOS using the USB Legacy Support capability at `offset`
+pub fn legacy_handoff(base: usize, size: usize, offset: usize) -> Result<(), XhciError> {
+    validate_region(base + offset, 4, base, size)?;
+    let bios_owned = unsafe { &mut *((base + offset + 2) as *mut Mmio<u8>) };
+    let os_owned = unsafe { &mut *((base + offset + 3) as *mut Mmio<u8>) };
+
+    os_owned.write(1);
+
+    let timeout = Timeout::from_millis(TIMEOUT_MS);
+    while bios_owned.readf(1) {
+        timeout.check()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocol_capability() {
+        let usb3 = ProtocolCapability::from_dwords(0x0300_0802, 0x0000_0401);
+        assert_eq!(usb3, ProtocolCapability { major: 3, minor: 0, port_offset: 1, port_count: 4, protocol_defined: 0, psic: 0 });
+        assert!(usb3.contains(4));
+        assert!(! usb3.contains(5));
+        assert!(! usb3.supports_l1());
+
+        let usb2 = ProtocolCapability::from_dwords(0x0200_0802, 0x0019_0605);
+        assert_eq!(usb2.protocol_defined, 0x19);
+        assert!(usb2.supports_l1());
+    }
+
+    #[test]
+    fn companion_ports() {
+        let protocols = [
+            ProtocolCapability { major: 3, minor: 0, port_offset: 1, port_count: 4, protocol_defined: 0, psic: 0 },
+            ProtocolCapability { major: 2, minor: 0, port_offset: 5, port_count: 6, protocol_defined: 0, psic: 0 },
+        ];
+        assert_eq!(companion_port(&protocols, 2), Some(6));
+        assert_eq!(companion_port(&protocols, 6), Some(2));
+        assert_eq!(companion_port(&protocols, 10), None);
+        assert_eq!(companion_port(&protocols, 11), None);
+    }
+
+    #[test]
+    fn extended_caps_stay_in_mapping() {
+        // Capabilities at dwords 1 and 2, the second pointing 255 dwords past the end
+        let regs: [u32; 3] = [0, 0x0000_0101, 0x0000_FF02];
+        let base = regs.as_ptr() as usize;
+        let caps: Vec<(u8, usize)> = walk_extended_caps(base, 12, 1 << 16).collect();
+        assert_eq!(caps, vec![(1, 4), (2, 8)]);
+
+        // xECP itself outside of the mapping
+        assert_eq!(walk_extended_caps(base, 12, 0x10 << 16).count(), 0);
+    }
+}
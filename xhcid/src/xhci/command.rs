@@ -1,20 +1,22 @@
-use syscall::error::Result;
-use syscall::io::Dma;
+use std::mem;
 
-use super::event::EventRing;
+use super::error::XhciError;
 use super::ring::Ring;
 use super::trb::Trb;
 
 pub struct CommandRing {
     pub ring: Ring,
-    pub events: EventRing,
+    /// Physical address of the oldest command TRB the controller has not completed yet
+    dequeue: u64,
 }
 
 impl CommandRing {
-    pub fn new() -> Result<CommandRing> {
+    pub fn new() -> Result<CommandRing, XhciError> {
+        let ring = Ring::new(true)?;
+        let dequeue = ring.trbs.physical() as u64;
         Ok(CommandRing {
-            ring: Ring::new(true)?,
-            events: EventRing::new()?,
+            ring: ring,
+            dequeue: dequeue,
         })
     }
 
@@ -22,25 +24,75 @@ impl CommandRing {
         self.ring.register()
     }
 
-    pub fn erdp(&self) -> u64 {
-        self.events.ring.register()
+    pub fn dequeue_ptr(&self) -> u64 {
+        self.dequeue
     }
 
-    pub fn erstba(&self) -> u64 {
-        self.events.ste.physical() as u64
+    /// Treat every queued command as consumed, after the controller has been reset
+    pub fn reset_dequeue_ptr(&mut self) {
+        self.dequeue = self.ring.register() & !0xF;
     }
 
-    pub fn next(&mut self) -> (&mut Trb, bool, &mut Trb) {
-        let cmd = self.ring.next();
-        let event = self.events.next();
-        (cmd.0, cmd.1, event)
+    /// Record the completion of the command TRB at `trb`, freeing it and every command before it
+    pub fn set_dequeue_ptr(&mut self, trb: u64) {
+        self.dequeue = trb + mem::size_of::<Trb>() as u64;
     }
 
-    pub fn next_cmd(&mut self) -> (&mut Trb, bool) {
-        self.ring.next()
+    /// Move the enqueue pointer back to `trb` after the ring was stopped there, turning the commands that
+    /// were queued after it into TRBs the controller will not execute
+    pub fn set_enqueue_ptr(&mut self, trb: u64) {
+        let i = self.index(trb);
+        // An enqueue pointer on the Link TRB is the same position as the start of the ring
+        let at_link = self.ring.i == self.ring.trbs.len() - 1;
+        while self.ring.i != i && ! (at_link && i == 0) {
+            if self.ring.i == 0 {
+                self.ring.i = self.ring.trbs.len() - 1;
+                self.ring.cycle = !self.ring.cycle;
+            }
+            self.ring.i -= 1;
+
+            let (index, cycle) = (self.ring.i, self.ring.cycle);
+            self.ring.trbs[index].reserved(!cycle);
+        }
+        self.dequeue = trb;
+    }
+
+    /// Index of a TRB on the ring, with the Link TRB mapped to the start of the ring it points back to
+    fn index(&self, ptr: u64) -> usize {
+        let base = self.ring.trbs.physical() as u64;
+        let i = (ptr.wrapping_sub(base) / mem::size_of::<Trb>() as u64) as usize;
+        if i >= self.ring.trbs.len() - 1 {
+            0
+        } else {
+            i
+        }
+    }
+
+    /// Number of commands that have been queued and not completed yet
+    pub fn pending_count(&self) -> usize {
+        // The Link TRB takes one entry of the ring
+        let usable = self.ring.trbs.len() - 1;
+        let enqueue = if self.ring.i >= usable { 0 } else { self.ring.i };
+        (enqueue + usable - self.index(self.dequeue)) % usable
+    }
+
+    /// Whether enqueueing another command would overwrite one the controller has not consumed
+    pub fn is_full(&self) -> bool {
+        // One entry stays empty so that a full ring can be told apart from an empty one
+        self.pending_count() >= self.ring.trbs.len() - 2
+    }
+
+    /// Physical address of the command TRB queued last, which its Command Completion event points to
+    pub fn last_cmd_ptr(&self) -> u64 {
+        let len = self.ring.trbs.len();
+        let i = (self.ring.i + len - 1) % len;
+        self.ring.trbs.physical() as u64 + (i * mem::size_of::<Trb>()) as u64
     }
 
-    pub fn next_event(&mut self) -> &mut Trb {
-        self.events.next()
+    pub fn next_cmd(&mut self) -> Result<(&mut Trb, bool), XhciError> {
+        if self.is_full() {
+            return Err(XhciError::RingFull);
+        }
+        Ok(self.ring.next())
     }
 }
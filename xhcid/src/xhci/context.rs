@@ -1,5 +1,55 @@
-use syscall::error::Result;
-use syscall::io::{Dma, Mmio};
+use std::cmp;
+use std::convert::TryFrom;
+use syscall::io::{Dma, Io, Mmio};
+use usb;
+
+use super::error::XhciError;
+use super::port::PortSpeed;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlotState {
+    /// Disabled or Enabled, which cannot be told apart from the context
+    Disabled,
+    Default,
+    Addressed,
+    Configured,
+    Reserved(u8),
+}
+
+impl From<u8> for SlotState {
+    fn from(state: u8) -> SlotState {
+        match state {
+            0 => SlotState::Disabled,
+            1 => SlotState::Default,
+            2 => SlotState::Addressed,
+            3 => SlotState::Configured,
+            reserved => SlotState::Reserved(reserved)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointState {
+    Disabled,
+    Running,
+    Halted,
+    Stopped,
+    Error,
+    Reserved(u8),
+}
+
+impl From<u8> for EndpointState {
+    fn from(state: u8) -> EndpointState {
+        match state {
+            0 => EndpointState::Disabled,
+            1 => EndpointState::Running,
+            2 => EndpointState::Halted,
+            3 => EndpointState::Stopped,
+            4 => EndpointState::Error,
+            reserved => EndpointState::Reserved(reserved)
+        }
+    }
+}
 
 #[repr(packed)]
 pub struct SlotContext {
@@ -10,6 +60,177 @@ pub struct SlotContext {
     _rsvd: [Mmio<u32>; 4],
 }
 
+impl SlotContext {
+    pub fn state(&self) -> SlotState {
+        SlotState::from((self.d.read() >> 27) as u8)
+    }
+
+    /// USB device address assigned by the controller, 0 until a Set Address request has been sent
+    pub fn usb_address(&self) -> u8 {
+        self.d.read() as u8
+    }
+
+    /// Index of the last valid endpoint context
+    pub fn context_entries(&self) -> u8 {
+        (self.a.read() >> 27) as u8
+    }
+
+    /// Number of downstream ports, only set for hubs
+    pub fn num_ports(&self) -> u8 {
+        (self.b.read() >> 24) as u8
+    }
+
+    pub fn interrupter_target(&self) -> u16 {
+        (self.c.read() >> 22) as u16
+    }
+
+    /// Route String, the hub port at each tier between the root hub port and the device
+    pub fn route_string(&self) -> u32 {
+        self.a.read() & 0xFFFFF
+    }
+
+    pub fn speed(&self) -> Option<PortSpeed> {
+        PortSpeed::try_from(((self.a.read() >> 20) & 0xF) as u8).ok()
+    }
+
+    /// Root hub port number the device is attached through, starting at 1
+    pub fn root_hub_port(&self) -> u8 {
+        (self.b.read() >> 16) as u8
+    }
+
+    /// Slot ID of the high speed hub whose transaction translator a full or low speed device is behind
+    pub fn tt_hub_slot(&self) -> u8 {
+        self.c.read() as u8
+    }
+
+    /// Port of the high speed hub a full or low speed device is behind, starting at 1
+    pub fn tt_port(&self) -> u8 {
+        (self.c.read() >> 8) as u8
+    }
+
+    /// Mark the slot as a hub with `num_ports` downstream ports, `ttt` is the TT Think Time field of the
+    /// hub descriptor and `mtt` is set for a high-speed hub with a transaction translator per port
+    pub fn set_hub(&mut self, num_ports: u8, mtt: bool, ttt: u8) {
+        let a = self.a.read() & !(0b11 << 25);
+        self.a.write(a | (1 << 26) | ((mtt as u32) << 25));
+        let b = self.b.read() & !(0xFF << 24);
+        self.b.write(b | ((num_ports as u32) << 24));
+        let c = self.c.read() & !(0b11 << 16);
+        self.c.write(c | ((ttt as u32 & 0b11) << 16));
+    }
+
+    /// Set the index of the last valid endpoint context
+    pub fn set_context_entries(&mut self, entries: u8) {
+        let a = self.a.read() & !(0x1F << 27);
+        self.a.write(a | ((entries as u32 & 0x1F) << 27));
+    }
+}
+
+/// Route String of a device, from the hub port numbers on the path from its root hub port, starting at 1
+///
+/// The root hub port is not part of the route string, so a device attached to it has an empty path.
+pub fn compute_route_string(path: &[u8]) -> Result<u32, XhciError> {
+    if path.len() > 5 {
+        return Err(XhciError::InvalidState("route string longer than 5 hubs"));
+    }
+
+    let mut route_string = 0;
+    for (tier, &port) in path.iter().enumerate() {
+        if port == 0 || port > 15 {
+            return Err(XhciError::InvalidState("hub port out of route string range"));
+        }
+        route_string |= (port as u32) << (tier * 4);
+    }
+    Ok(route_string)
+}
+
+/// Endpoint Type field of an endpoint context
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum EpType {
+    NotValid,
+    IsochOut,
+    BulkOut,
+    InterruptOut,
+    Control,
+    IsochIn,
+    BulkIn,
+    InterruptIn,
+}
+
+impl EpType {
+    pub fn new(kind: usb::EndpointKind, input: bool) -> EpType {
+        match (kind, input) {
+            (usb::EndpointKind::Control, _) => EpType::Control,
+            (usb::EndpointKind::Isoch, false) => EpType::IsochOut,
+            (usb::EndpointKind::Bulk, false) => EpType::BulkOut,
+            (usb::EndpointKind::Interrupt, false) => EpType::InterruptOut,
+            (usb::EndpointKind::Isoch, true) => EpType::IsochIn,
+            (usb::EndpointKind::Bulk, true) => EpType::BulkIn,
+            (usb::EndpointKind::Interrupt, true) => EpType::InterruptIn,
+        }
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        match *self {
+            EpType::IsochOut | EpType::IsochIn | EpType::InterruptOut | EpType::InterruptIn => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_isoch(&self) -> bool {
+        *self == EpType::IsochOut || *self == EpType::IsochIn
+    }
+
+    /// Transfer type of the endpoint, or `None` for an invalid endpoint
+    pub fn kind(&self) -> Option<usb::EndpointKind> {
+        match *self {
+            EpType::NotValid => None,
+            EpType::IsochOut | EpType::IsochIn => Some(usb::EndpointKind::Isoch),
+            EpType::BulkOut | EpType::BulkIn => Some(usb::EndpointKind::Bulk),
+            EpType::InterruptOut | EpType::InterruptIn => Some(usb::EndpointKind::Interrupt),
+            EpType::Control => Some(usb::EndpointKind::Control),
+        }
+    }
+}
+
+impl From<u8> for EpType {
+    fn from(ep_type: u8) -> EpType {
+        match ep_type & 0b111 {
+            1 => EpType::IsochOut,
+            2 => EpType::BulkOut,
+            3 => EpType::InterruptOut,
+            4 => EpType::Control,
+            5 => EpType::IsochIn,
+            6 => EpType::BulkIn,
+            7 => EpType::InterruptIn,
+            _ => EpType::NotValid,
+        }
+    }
+}
+
+/// Interval field of an endpoint context, an exponent of 125 us frames, from the bInterval of its descriptor
+///
+/// Only periodic endpoints have an interval, 0 is returned for the others.
+pub fn descriptor_interval_to_context_interval(speed: PortSpeed, ep_type: EpType, descriptor_interval: u8) -> u8 {
+    if ! ep_type.is_periodic() {
+        return 0;
+    }
+
+    match speed {
+        PortSpeed::FullSpeed | PortSpeed::LowSpeed => if ep_type.is_isoch() {
+            // Full speed isochronous intervals are already exponents, in 1 ms frames
+            cmp::min(cmp::max(descriptor_interval, 1), 16) + 2
+        } else {
+            // Other full and low speed intervals are in 1 ms frames, rounded down to a power of two
+            let frames = cmp::max(descriptor_interval, 1) as u32 * 8;
+            cmp::min(cmp::max(31 - frames.leading_zeros(), 3), 10) as u8
+        },
+        // High speed and SuperSpeed intervals are exponents of 125 us frames, plus one
+        _ => cmp::min(cmp::max(descriptor_interval, 1), 16) - 1,
+    }
+}
+
 #[repr(packed)]
 pub struct EndpointContext {
     pub a: Mmio<u32>,
@@ -20,45 +241,397 @@ pub struct EndpointContext {
     _rsvd: [Mmio<u32>; 3],
 }
 
-#[repr(packed)]
-pub struct DeviceContext {
-    pub slot: SlotContext,
-    pub endpoints: [EndpointContext; 15]
+impl EndpointContext {
+    pub fn state(&self) -> EndpointState {
+        EndpointState::from((self.a.read() & 0b111) as u8)
+    }
+
+    /// Interval field, an exponent of 125 us frames
+    pub fn interval(&self) -> u8 {
+        (self.a.read() >> 16) as u8
+    }
+
+    /// Endpoint Type field
+    pub fn ep_type(&self) -> EpType {
+        EpType::from(((self.b.read() >> 3) & 0b111) as u8)
+    }
+
+    /// Error Count, the number of consecutive bus errors allowed before the endpoint halts
+    pub fn error_count(&self) -> u8 {
+        ((self.b.read() >> 1) & 0b11) as u8
+    }
+
+    /// TR Dequeue Pointer, without the Dequeue Cycle State
+    pub fn dequeue(&self) -> u64 {
+        ((self.trh.read() as u64) << 32 | self.trl.read() as u64) & !0xF
+    }
+
+    /// Dequeue Cycle State
+    pub fn dcs(&self) -> bool {
+        self.trl.read() & 1 != 0
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        (self.b.read() >> 16) as u16
+    }
+
+    /// Use a linear primary stream context array of 2^(`max_primary_streams` + 1) entries at `array`
+    /// instead of a transfer ring
+    pub fn set_streams(&mut self, max_primary_streams: u8, array: u64) {
+        let a = self.a.read() & !(0x3F << 10);
+        self.a.write(a | (1 << 15) | ((max_primary_streams as u32 & 0x1F) << 10));
+        // The dequeue cycle state is not used for stream context arrays
+        self.trl.write(array as u32 & !0xF);
+        self.trh.write((array >> 32) as u32);
+    }
+
+    pub fn max_burst_size(&self) -> u8 {
+        (self.b.read() >> 8) as u8
+    }
+
+    pub fn max_esit_payload(&self) -> u32 {
+        ((self.a.read() >> 24) << 16) | (self.c.read() >> 16)
+    }
 }
 
 #[repr(packed)]
-pub struct InputContext {
+pub struct InputControlContext {
     pub drop_context: Mmio<u32>,
     pub add_context: Mmio<u32>,
     _rsvd: [Mmio<u32>; 5],
     pub control: Mmio<u32>,
-    pub device: DeviceContext,
 }
 
-pub struct DeviceContextList {
-    pub dcbaa: Dma<[u64; 256]>,
-    pub contexts: Vec<Dma<DeviceContext>>,
+/// Fields of an endpoint context, to be written to an input context with `build`
+#[derive(Clone, Copy, Debug)]
+pub struct EndpointContextBuilder {
+    max_packet_size: u16,
+    ep_type: EpType,
+    error_count: u8,
+    max_burst_size: u8,
+    mult: u8,
+    interval: u8,
+    max_esit_payload: u32,
+    average_trb_length: u16,
+    dequeue: u64,
+}
+
+impl EndpointContextBuilder {
+    pub fn new() -> EndpointContextBuilder {
+        EndpointContextBuilder {
+            max_packet_size: 0,
+            ep_type: EpType::NotValid,
+            error_count: 3,
+            max_burst_size: 0,
+            mult: 0,
+            interval: 0,
+            max_esit_payload: 0,
+            average_trb_length: 0,
+            dequeue: 0,
+        }
+    }
+
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    pub fn ep_type(mut self, ep_type: EpType) -> Self {
+        self.ep_type = ep_type;
+        self
+    }
+
+    /// Number of retries after a transaction error, 3 by default and 0 for isochronous endpoints
+    pub fn error_count(mut self, error_count: u8) -> Self {
+        self.error_count = error_count;
+        self
+    }
+
+    /// Additional packets per burst
+    pub fn max_burst_size(mut self, max_burst_size: u8) -> Self {
+        self.max_burst_size = max_burst_size;
+        self
+    }
+
+    /// Additional bursts per interval of SuperSpeed isochronous endpoints
+    pub fn mult(mut self, mult: u8) -> Self {
+        self.mult = mult;
+        self
+    }
+
+    /// Interval as an exponent of 125 us frames, see `descriptor_interval_to_context_interval`
+    pub fn interval(mut self, interval: u8) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Bytes transferred by a periodic endpoint each interval, split across two fields of the context
+    pub fn max_esit_payload(mut self, max_esit_payload: u32) -> Self {
+        self.max_esit_payload = max_esit_payload;
+        self
+    }
+
+    pub fn average_trb_length(mut self, average_trb_length: u16) -> Self {
+        self.average_trb_length = average_trb_length;
+        self
+    }
+
+    /// Physical address of the transfer ring, with the dequeue cycle state in bit 0
+    pub fn dequeue(mut self, dequeue: u64) -> Self {
+        self.dequeue = dequeue;
+        self
+    }
+
+    pub fn build(self, context: &mut EndpointContext) {
+        context.a.write(
+            ((self.max_esit_payload >> 16) << 24) |
+            ((self.interval as u32) << 16) |
+            (((self.mult as u32) & 0b11) << 8)
+        );
+        context.b.write(
+            ((self.max_packet_size as u32) << 16) |
+            ((self.max_burst_size as u32) << 8) |
+            ((self.ep_type as u32) << 3) |
+            (((self.error_count as u32) & 0b11) << 1)
+        );
+        context.trl.write(self.dequeue as u32);
+        context.trh.write((self.dequeue >> 32) as u32);
+        context.c.write(
+            ((self.max_esit_payload & 0xFFFF) << 16) |
+            (self.average_trb_length as u32)
+        );
+    }
+}
+
+/// Largest context size, contexts are 64 bytes instead of 32 when HCCPARAMS1.CSZ is set
+pub const MAX_CONTEXT_SIZE: usize = 64;
+
+/// Check that a context size is one the controller can report
+fn check_context_size(size: usize) -> Result<(), XhciError> {
+    if size == 32 || size == 64 {
+        Ok(())
+    } else {
+        Err(XhciError::InvalidState("context size must be 32 or 64 bytes"))
+    }
+}
+
+/// Output device context of a slot, a slot context followed by 31 endpoint contexts of `size` bytes each
+///
+/// Only the first 32 bytes of each context are used, the rest is reserved when contexts are 64 bytes.
+pub struct DeviceContext {
+    data: Dma<[u8; 32 * MAX_CONTEXT_SIZE]>,
+    size: usize,
+}
+
+impl DeviceContext {
+    pub fn new(size: usize) -> Result<DeviceContext, XhciError> {
+        check_context_size(size)?;
+        Ok(DeviceContext {
+            data: Dma::zeroed().or(Err(XhciError::DmaAlloc))?,
+            size: size,
+        })
+    }
+
+    pub fn physical(&self) -> usize {
+        self.data.physical()
+    }
+
+    pub fn slot(&self) -> &SlotContext {
+        unsafe { &*(self.data.as_ptr() as *const SlotContext) }
+    }
+
+    /// Endpoint context of endpoint ID `i + 1`
+    pub fn endpoint(&self, i: usize) -> &EndpointContext {
+        assert!(i < 31);
+        unsafe { &*(self.data.as_ptr().offset(((i + 1) * self.size) as isize) as *const EndpointContext) }
+    }
+
+    pub fn clear(&mut self) {
+        for byte in self.data.iter_mut() {
+            *byte = 0;
+        }
+    }
+}
+
+/// Input context, an input control context followed by a device context, with contexts of `size` bytes each
+pub struct InputContext {
+    data: Dma<[u8; 33 * MAX_CONTEXT_SIZE]>,
+    size: usize,
 }
 
-impl DeviceContextList {
-    pub fn new(max_slots: u8) -> Result<DeviceContextList> {
-        let mut dcbaa = Dma::<[u64; 256]>::zeroed()?;
-        let mut contexts = vec![];
+impl InputContext {
+    pub fn new(size: usize) -> Result<InputContext, XhciError> {
+        check_context_size(size)?;
+        Ok(InputContext {
+            data: Dma::zeroed().or(Err(XhciError::DmaAlloc))?,
+            size: size,
+        })
+    }
+
+    pub fn physical(&self) -> usize {
+        self.data.physical()
+    }
+
+    pub fn control(&self) -> &InputControlContext {
+        unsafe { &*(self.data.as_ptr() as *const InputControlContext) }
+    }
+
+    pub fn control_mut(&mut self) -> &mut InputControlContext {
+        unsafe { &mut *(self.data.as_mut_ptr() as *mut InputControlContext) }
+    }
 
-        // Create device context buffers for each slot
-        for i in 0..max_slots as usize {
-            let context: Dma<DeviceContext> = Dma::zeroed()?;
-            dcbaa[i] = context.physical() as u64;
-            contexts.push(context);
+    /// Mark the slot context to be evaluated, bit 0 of the add context flags
+    pub fn add_slot(&mut self) {
+        self.control_mut().add_context.writef(1, true);
+    }
+
+    /// Clear the slot context's add flag, the slot context cannot be dropped
+    pub fn drop_slot(&mut self) {
+        self.control_mut().add_context.writef(1, false);
+    }
+
+    /// Mark the context of endpoint ID `endpoint_id` (1 for EP0) to be added or changed
+    pub fn add_endpoint(&mut self, endpoint_id: u8) {
+        assert!(endpoint_id >= 1 && endpoint_id <= 31);
+        self.control_mut().add_context.writef(1 << endpoint_id, true);
+    }
+
+    /// Mark the context of endpoint ID `endpoint_id` to be dropped, which is not allowed for EP0
+    pub fn drop_endpoint(&mut self, endpoint_id: u8) {
+        assert!(endpoint_id >= 2 && endpoint_id <= 31);
+        self.control_mut().drop_context.writef(1 << endpoint_id, true);
+    }
+
+    pub fn slot_mut(&mut self) -> &mut SlotContext {
+        unsafe { &mut *(self.data.as_mut_ptr().offset(self.size as isize) as *mut SlotContext) }
+    }
+
+    /// Set the fields of the slot context that describe where the device is in the USB topology, keeping
+    /// the context entries
+    ///
+    /// `num_ports` is only non-zero for hubs, and `tt_slot` and `tt_port` locate the transaction translator
+    /// of full and low speed devices behind a high speed hub.
+    pub fn set_slot_context(&mut self, route_string: u32, speed: PortSpeed, num_ports: u8, rh_port: u8, tt_slot: u8, tt_port: u8, interrupter_target: u16) {
+        let slot = self.slot_mut();
+
+        let entries = slot.a.read() & (0x1F << 27);
+        slot.a.write(
+            entries |
+            (((num_ports > 0) as u32) << 26) |
+            ((speed as u32) << 20) |
+            (route_string & 0xFFFFF)
+        );
+        slot.b.write(
+            ((num_ports as u32) << 24) |
+            ((rh_port as u32) << 16)
+        );
+        slot.c.write(
+            (((interrupter_target as u32) & 0x3FF) << 22) |
+            ((tt_port as u32) << 8) |
+            (tt_slot as u32)
+        );
+    }
+
+    /// Copy the slot context of a device context, so that the fields set when addressing the slot are kept
+    /// when it is configured
+    pub fn copy_slot_context(&mut self, other: &SlotContext) {
+        let slot = self.slot_mut();
+        slot.a.write(other.a.read());
+        slot.b.write(other.b.read());
+        slot.c.write(other.c.read());
+        // The slot state and USB address are output fields
+        slot.d.write(0);
+    }
+
+    /// Copy an endpoint context of a device context into endpoint context `i`, to change some of its fields
+    pub fn copy_endpoint_context(&mut self, i: usize, other: &EndpointContext) {
+        let endpoint = self.endpoint_mut(i);
+        // The endpoint state is an output field
+        endpoint.a.write(other.a.read() & !0b111);
+        endpoint.b.write(other.b.read());
+        endpoint.trl.write(other.trl.read());
+        endpoint.trh.write(other.trh.read());
+        endpoint.c.write(other.c.read());
+    }
+
+    /// Endpoint context of endpoint ID `i + 1`
+    pub fn endpoint_mut(&mut self, i: usize) -> &mut EndpointContext {
+        assert!(i < 31);
+        unsafe { &mut *(self.data.as_mut_ptr().offset(((i + 2) * self.size) as isize) as *mut EndpointContext) }
+    }
+}
+
+/// Size of the pages handed to the controller as scratchpad, assuming the default 4K PAGESIZE
+pub const PAGE_SIZE: usize = 4096;
+
+pub struct ScratchpadBufferArray {
+    pub entries: Dma<[u64; 1024]>,
+    pub pages: Vec<Dma<[u8; PAGE_SIZE]>>,
+}
+
+impl ScratchpadBufferArray {
+    pub fn new(count: u32) -> Result<ScratchpadBufferArray, XhciError> {
+        let mut entries = Dma::<[u64; 1024]>::zeroed().or(Err(XhciError::DmaAlloc))?;
+        let mut pages = vec![];
+
+        for i in 0..count as usize {
+            let page: Dma<[u8; PAGE_SIZE]> = Dma::zeroed().or(Err(XhciError::DmaAlloc))?;
+            entries[i] = page.physical() as u64;
+            pages.push(page);
         }
 
-        Ok(DeviceContextList {
-            dcbaa: dcbaa,
-            contexts: contexts
+        Ok(ScratchpadBufferArray {
+            entries: entries,
+            pages: pages
         })
     }
 
-    pub fn dcbaap(&self) -> u64 {
-        self.dcbaa.physical() as u64
+    pub fn register(&self) -> u64 {
+        self.entries.physical() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compute_route_string, descriptor_interval_to_context_interval, EpType};
+    use super::super::port::PortSpeed;
+
+    #[test]
+    fn interval_high_speed() {
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::HighSpeed, EpType::InterruptIn, 1), 0);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::HighSpeed, EpType::InterruptIn, 4), 3);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::SuperSpeed, EpType::IsochOut, 16), 15);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::SuperSpeed, EpType::IsochOut, 0), 0);
+    }
+
+    #[test]
+    fn interval_full_speed() {
+        // 1 ms is 8 frames of 125 us
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::FullSpeed, EpType::InterruptIn, 1), 3);
+        // 10 ms is rounded down to 8 ms
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::LowSpeed, EpType::InterruptIn, 10), 6);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::FullSpeed, EpType::InterruptOut, 255), 10);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::FullSpeed, EpType::IsochIn, 1), 3);
+    }
+
+    #[test]
+    fn interval_not_periodic() {
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::HighSpeed, EpType::BulkIn, 4), 0);
+        assert_eq!(descriptor_interval_to_context_interval(PortSpeed::FullSpeed, EpType::Control, 4), 0);
+    }
+
+    #[test]
+    fn route_string() {
+        assert_eq!(compute_route_string(&[]), Ok(0));
+        assert_eq!(compute_route_string(&[3]), Ok(0x3));
+        assert_eq!(compute_route_string(&[1, 2, 15]), Ok(0xF21));
+        assert_eq!(compute_route_string(&[1, 2, 3, 4, 5]), Ok(0x54321));
+    }
+
+    #[test]
+    fn route_string_invalid() {
+        assert!(compute_route_string(&[1, 2, 3, 4, 5, 6]).is_err());
+        assert!(compute_route_string(&[16]).is_err());
+        assert!(compute_route_string(&[1, 0]).is_err());
     }
 }
@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet};
+use syscall::io::Dma;
+use usb;
+
+use super::context::{DeviceContext, InputContext, SlotState};
+use super::error::XhciError;
+use super::stream::StreamContextArray;
+use super::transfer_ring::TransferRing;
+
+/// Size of the buffers kept for interrupt endpoints, enough for the largest high-bandwidth interrupt payload
+pub const INTERRUPT_BUFFER_SIZE: usize = 4096;
+
+pub type InterruptBuffer = Dma<[u8; INTERRUPT_BUFFER_SIZE]>;
+
+/// Size of the bounce buffer used for the data stage of control transfers
+pub const CONTROL_BUFFER_SIZE: usize = 4096;
+
+pub type ControlBuffer = Dma<[u8; CONTROL_BUFFER_SIZE]>;
+
+/// Strings named by a device descriptor, empty if the device has none
+#[derive(Clone, Debug, Default)]
+pub struct DeviceStrings {
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+}
+
+pub struct DeviceList {
+    pub dcbaa: Dma<[u64; 256]>,
+    pub contexts: Vec<DeviceContext>,
+    in_use: Vec<bool>,
+    /// Input contexts of addressed slots, kept alive while the controller may still reference them
+    inputs: BTreeMap<u8, InputContext>,
+    /// Bounce buffers for the data stage of control transfers on the default control endpoint of each slot
+    control_buffers: BTreeMap<u8, ControlBuffer>,
+    /// Transfer rings keyed by slot and endpoint ID
+    rings: BTreeMap<(u8, u8), TransferRing>,
+    /// Stream context arrays of endpoints using streams, keyed by slot and endpoint ID
+    stream_arrays: BTreeMap<(u8, u8), StreamContextArray>,
+    /// Transfer rings of streams, keyed by slot, endpoint ID and stream ID
+    streams: BTreeMap<(u8, u8, u16), TransferRing>,
+    /// Buffers and transfer lengths of interrupt endpoints, kept to be resubmitted after each completion
+    interrupts: BTreeMap<(u8, u8), (InterruptBuffer, usize)>,
+    /// Device descriptors of addressed slots
+    descriptors: BTreeMap<u8, usb::DeviceDescriptor>,
+    strings: BTreeMap<u8, DeviceStrings>,
+    /// USB device addresses assigned to addressed slots
+    addresses: BTreeMap<u8, u8>,
+    /// Slots of devices that have remote wakeup enabled
+    remote_wakeup: BTreeSet<u8>,
+    /// Baseline BESL of USB 2.0 devices that support LPM, from their BOS descriptor
+    lpm_besl: BTreeMap<u8, u8>,
+}
+
+impl DeviceList {
+    pub fn new(max_slots: u8, context_size: usize) -> Result<DeviceList, XhciError> {
+        let mut dcbaa = Dma::<[u64; 256]>::zeroed().or(Err(XhciError::DmaAlloc))?;
+        let mut contexts = vec![];
+
+        // Create device context buffers for each slot, entry 0 is reserved for the scratchpad
+        for i in 0..max_slots as usize {
+            let context = DeviceContext::new(context_size)?;
+            dcbaa[i + 1] = context.physical() as u64;
+            contexts.push(context);
+        }
+
+        Ok(DeviceList {
+            dcbaa: dcbaa,
+            contexts: contexts,
+            in_use: vec![false; max_slots as usize],
+            inputs: BTreeMap::new(),
+            control_buffers: BTreeMap::new(),
+            rings: BTreeMap::new(),
+            stream_arrays: BTreeMap::new(),
+            streams: BTreeMap::new(),
+            interrupts: BTreeMap::new(),
+            descriptors: BTreeMap::new(),
+            strings: BTreeMap::new(),
+            addresses: BTreeMap::new(),
+            remote_wakeup: BTreeSet::new(),
+            lpm_besl: BTreeMap::new(),
+        })
+    }
+
+    pub fn dcbaap(&self) -> u64 {
+        self.dcbaa.physical() as u64
+    }
+
+    pub fn dcbaap_entry(&mut self, slot: u8) -> &mut u64 {
+        &mut self.dcbaa[slot as usize]
+    }
+
+    /// Output device context of a slot, written by the controller
+    pub fn context(&self, slot: u8) -> Result<&DeviceContext, XhciError> {
+        if slot == 0 {
+            return Err(XhciError::InvalidState("slot 0 is reserved"));
+        }
+        self.contexts.get(slot as usize - 1).ok_or(XhciError::InvalidState("slot out of range"))
+    }
+
+    pub fn is_used(&self, slot: u8) -> bool {
+        slot > 0 && self.in_use.get(slot as usize - 1).cloned().unwrap_or(false)
+    }
+
+    /// Slots with an output context in the DCBAA that are in the Addressed or Configured state
+    pub fn iter_connected<'a>(&'a self) -> impl Iterator<Item = (u8, &'a DeviceContext)> + 'a {
+        self.contexts.iter().enumerate().filter_map(move |(i, context)| {
+            let slot = i as u8 + 1;
+            if self.dcbaa[slot as usize] == 0 {
+                return None;
+            }
+
+            match context.slot().state() {
+                SlotState::Addressed | SlotState::Configured => Some((slot, context)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Slots of all devices reached through a root hub port, including the ones behind hubs
+    pub fn slots_for_port(&self, port: u8) -> Vec<u8> {
+        (1..self.contexts.len() as u16 + 1).map(|slot| slot as u8).filter(|&slot| {
+            self.is_used(slot) && self.contexts[slot as usize - 1].slot().root_hub_port() == port
+        }).collect()
+    }
+
+    /// Used slot of the device attached to a root hub port, starting at 1
+    pub fn slot_for_port(&self, port: u8) -> Option<u8> {
+        (1..self.contexts.len() as u16 + 1).map(|slot| slot as u8).find(|&slot| {
+            let context = self.contexts[slot as usize - 1].slot();
+            self.is_used(slot) && context.root_hub_port() == port && context.route_string() == 0
+        })
+    }
+
+    pub fn set_input_context(&mut self, slot: u8, input: InputContext) {
+        self.inputs.insert(slot, input);
+    }
+
+    pub fn input_context(&mut self, slot: u8) -> Result<&mut InputContext, XhciError> {
+        self.inputs.get_mut(&slot).ok_or(XhciError::InvalidState("slot has no input context"))
+    }
+
+    /// Transfer ring of the default control endpoint of a slot
+    pub fn transfer_ring(&mut self, slot: u8) -> Result<&mut TransferRing, XhciError> {
+        self.ring(slot, 1)
+    }
+
+    /// Replace the transfer ring of an endpoint by a stream context array and the rings of its streams
+    pub fn set_streams(&mut self, slot: u8, endpoint_id: u8, array: StreamContextArray, rings: Vec<(u16, TransferRing)>) {
+        self.rings.remove(&(slot, endpoint_id));
+        self.stream_arrays.insert((slot, endpoint_id), array);
+        for (stream_id, ring) in rings {
+            self.streams.insert((slot, endpoint_id, stream_id), ring);
+        }
+    }
+
+    pub fn stream_ring(&mut self, slot: u8, endpoint_id: u8, stream_id: u16) -> Result<&mut TransferRing, XhciError> {
+        self.streams.get_mut(&(slot, endpoint_id, stream_id)).ok_or(XhciError::InvalidState("stream has no transfer ring"))
+    }
+
+    /// Number of TRBs queued on an endpoint, including those of its streams, that the controller has not completed
+    pub fn queued_trbs(&self, slot: u8, endpoint_id: u8) -> usize {
+        let ring = self.rings.get(&(slot, endpoint_id)).map(|ring| ring.used_slots()).unwrap_or(0);
+        let streams: usize = self.streams.iter()
+            .filter(|&(&(stream_slot, stream_endpoint, _), _)| stream_slot == slot && stream_endpoint == endpoint_id)
+            .map(|(_, ring)| ring.used_slots())
+            .sum();
+        ring + streams
+    }
+
+    /// Transfer ring of an endpoint, or of one of its streams, that holds the TRB at `trb`
+    pub fn ring_containing(&mut self, slot: u8, endpoint_id: u8, trb: u64) -> Option<&mut TransferRing> {
+        if self.rings.get(&(slot, endpoint_id)).map(|ring| ring.contains(trb)).unwrap_or(false) {
+            return self.rings.get_mut(&(slot, endpoint_id));
+        }
+        self.streams.iter_mut().find(|entry| {
+            let &(stream_slot, stream_endpoint, _) = entry.0;
+            stream_slot == slot && stream_endpoint == endpoint_id && entry.1.contains(trb)
+        }).map(|(_, ring)| ring)
+    }
+
+    pub fn set_control_buffer(&mut self, slot: u8, buffer: ControlBuffer) {
+        self.control_buffers.insert(slot, buffer);
+    }
+
+    pub fn control_buffer(&mut self, slot: u8) -> Result<&mut ControlBuffer, XhciError> {
+        self.control_buffers.get_mut(&slot).ok_or(XhciError::InvalidState("slot has no control buffer"))
+    }
+
+    pub fn add_ring(&mut self, slot: u8, endpoint_id: u8, ring: TransferRing) {
+        self.rings.insert((slot, endpoint_id), ring);
+    }
+
+    pub fn ring(&mut self, slot: u8, endpoint_id: u8) -> Result<&mut TransferRing, XhciError> {
+        self.rings.get_mut(&(slot, endpoint_id)).ok_or(XhciError::InvalidState("endpoint has no transfer ring"))
+    }
+
+    pub fn set_descriptor(&mut self, slot: u8, descriptor: usb::DeviceDescriptor) {
+        self.descriptors.insert(slot, descriptor);
+    }
+
+    /// Device descriptor of a slot, if it has been fetched
+    pub fn descriptor(&self, slot: u8) -> Option<&usb::DeviceDescriptor> {
+        self.descriptors.get(&slot)
+    }
+
+    pub fn set_strings(&mut self, slot: u8, strings: DeviceStrings) {
+        self.strings.insert(slot, strings);
+    }
+
+    /// Manufacturer, product and serial strings of a slot, if they have been fetched
+    pub fn strings(&self, slot: u8) -> Option<&DeviceStrings> {
+        self.strings.get(&slot)
+    }
+
+    pub fn set_usb_address(&mut self, slot: u8, address: u8) {
+        self.addresses.insert(slot, address);
+    }
+
+    pub fn set_remote_wakeup(&mut self, slot: u8) {
+        self.remote_wakeup.insert(slot);
+    }
+
+    pub fn remote_wakeup_slots(&self) -> Vec<u8> {
+        self.remote_wakeup.iter().cloned().collect()
+    }
+
+    pub fn set_lpm_besl(&mut self, slot: u8, besl: u8) {
+        self.lpm_besl.insert(slot, besl);
+    }
+
+    /// Baseline BESL of a slot, if its device supports USB 2.0 LPM
+    pub fn lpm_besl(&self, slot: u8) -> Option<u8> {
+        self.lpm_besl.get(&slot).cloned()
+    }
+
+    /// USB device address of a slot, if it has been addressed
+    pub fn usb_address(&self, slot: u8) -> Option<u8> {
+        self.addresses.get(&slot).cloned()
+    }
+
+    pub fn slot_for_address(&self, address: u8) -> Option<u8> {
+        self.addresses.iter().find(|&(_, &slot_address)| slot_address == address).map(|(&slot, _)| slot)
+    }
+
+    pub fn set_interrupt_buffer(&mut self, slot: u8, endpoint_id: u8, buffer: InterruptBuffer, len: usize) {
+        self.interrupts.insert((slot, endpoint_id), (buffer, len));
+    }
+
+    pub fn interrupt_buffer(&self, slot: u8, endpoint_id: u8) -> Result<&[u8], XhciError> {
+        let &(ref buffer, len) = self.interrupts.get(&(slot, endpoint_id)).ok_or(XhciError::InvalidState("endpoint has no interrupt buffer"))?;
+        Ok(&buffer[..len])
+    }
+
+    /// Queue the interrupt buffer of an endpoint again on its transfer ring
+    pub fn resubmit_interrupt(&mut self, slot: u8, endpoint_id: u8) -> Result<(), XhciError> {
+        let &(ref buffer, len) = self.interrupts.get(&(slot, endpoint_id)).ok_or(XhciError::InvalidState("endpoint has no interrupt buffer"))?;
+        let ring = self.rings.get_mut(&(slot, endpoint_id)).ok_or(XhciError::InvalidState("endpoint has no transfer ring"))?;
+        ring.submit_interrupt(buffer, len)
+    }
+
+    /// Mark a slot enabled by the controller as used, giving it a clean output context
+    pub fn alloc_slot(&mut self, slot: u8) -> Result<(), XhciError> {
+        if slot == 0 || slot as usize > self.in_use.len() {
+            return Err(XhciError::InvalidState("slot out of range"));
+        }
+        if self.in_use[slot as usize - 1] {
+            return Err(XhciError::InvalidState("slot already in use"));
+        }
+
+        let context = &mut self.contexts[slot as usize - 1];
+        context.clear();
+        self.dcbaa[slot as usize] = context.physical() as u64;
+        self.in_use[slot as usize - 1] = true;
+
+        Ok(())
+    }
+
+    /// Forget everything learned about the device of a slot after a Reset Device Command, except the
+    /// transfer ring of its default control endpoint, which the controller keeps
+    pub fn reset_slot(&mut self, slot: u8) {
+        self.forget_device(slot, 2);
+    }
+
+    /// Mark a slot as free after it has been disabled, removing its output context from the DCBAA
+    pub fn free_slot(&mut self, slot: u8) {
+        if slot > 0 && (slot as usize) <= self.in_use.len() {
+            *self.dcbaap_entry(slot) = 0;
+            self.in_use[slot as usize - 1] = false;
+            self.inputs.remove(&slot);
+            self.control_buffers.remove(&slot);
+            self.forget_device(slot, 1);
+        }
+    }
+
+    /// Drop what was learned about the device of a slot, along with the transfer rings, stream context arrays and
+    /// stream rings of its endpoints from `first_endpoint_id` on
+    fn forget_device(&mut self, slot: u8, first_endpoint_id: u8) {
+        self.descriptors.remove(&slot);
+        self.strings.remove(&slot);
+        self.addresses.remove(&slot);
+        self.remote_wakeup.remove(&slot);
+        self.lpm_besl.remove(&slot);
+
+        let endpoints: Vec<(u8, u8)> = self.rings.keys().filter(|&&(ring_slot, endpoint_id)| ring_slot == slot && endpoint_id >= first_endpoint_id).cloned().collect();
+        for endpoint in endpoints {
+            self.rings.remove(&endpoint);
+            self.interrupts.remove(&endpoint);
+        }
+
+        let arrays: Vec<(u8, u8)> = self.stream_arrays.keys().filter(|&&(array_slot, endpoint_id)| array_slot == slot && endpoint_id >= first_endpoint_id).cloned().collect();
+        for endpoint in arrays {
+            self.stream_arrays.remove(&endpoint);
+        }
+        let streams: Vec<(u8, u8, u16)> = self.streams.keys().filter(|&&(stream_slot, endpoint_id, _)| stream_slot == slot && endpoint_id >= first_endpoint_id).cloned().collect();
+        for stream in streams {
+            self.streams.remove(&stream);
+        }
+    }
+}
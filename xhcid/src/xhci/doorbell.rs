@@ -11,4 +11,14 @@ impl Doorbell {
     pub fn write(&mut self, data: u32) {
         self.0.write(data);
     }
+
+    /// Ring the host controller doorbell (doorbell 0) to process the command ring
+    pub fn ring_command(&mut self) {
+        self.write(0);
+    }
+
+    /// Ring a device slot doorbell for an endpoint ID, and a stream ID if the endpoint has streams enabled
+    pub fn ring_endpoint(&mut self, endpoint_id: u8, stream_id: u16) {
+        self.write(((stream_id as u32) << 16) | (endpoint_id as u32));
+    }
 }
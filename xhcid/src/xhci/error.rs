@@ -0,0 +1,54 @@
+use std::fmt;
+use syscall::error::{Error, EBUSY, EINVAL, EIO, ENOMEM, ETIMEDOUT};
+
+#[derive(Debug, PartialEq)]
+pub enum XhciError {
+    /// The controller did not respond in time
+    ControllerTimeout,
+    /// A DMA buffer could not be allocated
+    DmaAlloc,
+    /// A command or transfer completed with a code other than success
+    BadCompletionCode(u8),
+    /// A port failed to reach the expected state
+    PortError(usize),
+    /// A ring has no free TRBs left
+    RingFull,
+    /// The controller or device is not in a state that allows the operation
+    InvalidState(&'static str),
+    /// Any other error returned by a system call
+    Syscall(Error),
+}
+
+impl From<Error> for XhciError {
+    fn from(err: Error) -> XhciError {
+        XhciError::Syscall(err)
+    }
+}
+
+impl From<XhciError> for Error {
+    fn from(err: XhciError) -> Error {
+        match err {
+            XhciError::ControllerTimeout => Error::new(ETIMEDOUT),
+            XhciError::DmaAlloc => Error::new(ENOMEM),
+            XhciError::BadCompletionCode(_) => Error::new(EIO),
+            XhciError::PortError(_) => Error::new(EIO),
+            XhciError::RingFull => Error::new(EBUSY),
+            XhciError::InvalidState(_) => Error::new(EINVAL),
+            XhciError::Syscall(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for XhciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XhciError::ControllerTimeout => write!(f, "controller timeout"),
+            XhciError::DmaAlloc => write!(f, "failed to allocate DMA memory"),
+            XhciError::BadCompletionCode(code) => write!(f, "bad completion code {}", code),
+            XhciError::PortError(port) => write!(f, "error on port {}", port),
+            XhciError::RingFull => write!(f, "ring full"),
+            XhciError::InvalidState(reason) => write!(f, "invalid state: {}", reason),
+            XhciError::Syscall(ref err) => write!(f, "{}", err),
+        }
+    }
+}
@@ -1,8 +1,126 @@
-use syscall::error::Result;
+use std::collections::VecDeque;
+use std::mem;
 use syscall::io::{Dma, Io, Mmio};
 
-use super::ring::Ring;
-use super::trb::Trb;
+use super::error::XhciError;
+use super::runtime::InterrupterRegs;
+use super::timeout::Timeout;
+use super::trb::{Trb, TrbType};
+use super::TIMEOUT_MS;
+
+/// Event Handler Busy, cleared by writing one to ERDP
+const ERDP_EHB: u64 = 1 << 3;
+
+/// Number of TRBs in each event ring segment
+pub const SEGMENT_SIZE: usize = 256;
+
+/// Most segments an event ring can have, the controller may support fewer
+pub const MAX_SEGMENTS: usize = 16;
+
+/// Event Data flag of a Transfer Event, set if it was generated by an Event Data TRB
+const TRANSFER_EVENT_ED: u32 = 1 << 2;
+
+/// Notification Type of a Device Notification TP
+const NOTIFICATION_FUNCTION_WAKE: u8 = 1;
+const NOTIFICATION_LATENCY_TOLERANCE: u8 = 2;
+
+/// Notification sent by a USB 3.x device without being polled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceNotification {
+    /// A function of a suspended device wants to resume
+    FunctionWake { slot: u8, interface_number: u8 },
+    /// New Best Effort Latency Tolerance of the device, a 10 bit value and a 2 bit scale
+    LatencyTolerance { belt_value: u16 },
+    Unknown { notification_type: u8, notification_info: u64 },
+}
+
+impl DeviceNotification {
+    /// Decode the Notification Type and Device Notification Data of a Device Notification Event
+    pub fn new(slot: u8, data: u64) -> DeviceNotification {
+        let notification_type = ((data >> 4) & 0xF) as u8;
+        match notification_type {
+            NOTIFICATION_FUNCTION_WAKE => DeviceNotification::FunctionWake {
+                slot: slot,
+                interface_number: (data >> 8) as u8,
+            },
+            NOTIFICATION_LATENCY_TOLERANCE => DeviceNotification::LatencyTolerance {
+                belt_value: ((data >> 8) & 0xFFF) as u16,
+            },
+            _ => DeviceNotification::Unknown {
+                notification_type: notification_type,
+                notification_info: data >> 8,
+            },
+        }
+    }
+}
+
+/// An event TRB decoded from the event ring
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XhciEvent {
+    Transfer { slot: u8, endpoint_id: u8, code: u8, residual: u32, trb: u64 },
+    /// Transfer Event generated by an Event Data TRB, with the number of bytes transferred by the TD
+    EventData { slot: u8, endpoint_id: u8, code: u8, transferred: u32, cookie: u64 },
+    CommandCompletion { slot: u8, code: u8, trb: u64 },
+    /// Port ID, starting at 1
+    PortStatusChange { port: u8 },
+    DeviceNotification { slot: u8, code: u8, notification: DeviceNotification },
+    /// MFINDEX wrapped from 0x3FFF to 0
+    MfindexWrap { code: u8 },
+    Other { kind: u8, data: u64, status: u32, control: u32 },
+}
+
+impl XhciEvent {
+    pub fn new(data: u64, status: u32, control: u32) -> XhciEvent {
+        let kind = ((control >> 10) & 0x3F) as u8;
+        let code = (status >> 24) as u8;
+        let slot = (control >> 24) as u8;
+
+        if kind == TrbType::Transfer as u8 && control & TRANSFER_EVENT_ED != 0 {
+            XhciEvent::EventData {
+                slot: slot,
+                endpoint_id: ((control >> 16) & 0x1F) as u8,
+                code: code,
+                transferred: status & 0xFFFFFF,
+                cookie: data,
+            }
+        } else if kind == TrbType::Transfer as u8 {
+            XhciEvent::Transfer {
+                slot: slot,
+                endpoint_id: ((control >> 16) & 0x1F) as u8,
+                code: code,
+                residual: status & 0xFFFFFF,
+                trb: data,
+            }
+        } else if kind == TrbType::CommandCompletion as u8 {
+            XhciEvent::CommandCompletion {
+                slot: slot,
+                code: code,
+                trb: data,
+            }
+        } else if kind == TrbType::PortStatusChange as u8 {
+            XhciEvent::PortStatusChange {
+                port: (data >> 24) as u8,
+            }
+        } else if kind == TrbType::DeviceNotification as u8 {
+            XhciEvent::DeviceNotification {
+                slot: slot,
+                code: code,
+                notification: DeviceNotification::new(slot, data),
+            }
+        } else if kind == TrbType::MfindexWrap as u8 {
+            XhciEvent::MfindexWrap {
+                code: code,
+            }
+        } else {
+            XhciEvent::Other {
+                kind: kind,
+                data: data,
+                status: status,
+                control: control,
+            }
+        }
+    }
+}
 
 #[repr(packed)]
 pub struct EventRingSte {
@@ -12,25 +130,157 @@ pub struct EventRingSte {
     _rsvd2: Mmio<u32>,
 }
 
+/// Event ring of an interrupter, written by the controller and consumed by the driver
 pub struct EventRing {
-    pub ste: Dma<EventRingSte>,
-    pub ring: Ring,
+    /// Segment table, with one entry for each segment
+    pub ste: Dma<[EventRingSte; MAX_SEGMENTS]>,
+    pub segments: Vec<Dma<[Trb; SEGMENT_SIZE]>>,
+    /// Segment and index of the next TRB to consume
+    segment: usize,
+    dequeue: usize,
+    /// Consumer cycle state, the controller starts producing events with the cycle bit set
+    cycle: bool,
+    /// Events skipped while waiting for a specific event type, to be handled later
+    pending: VecDeque<XhciEvent>,
+    /// Number of MFINDEX Wrap events seen, each one is 2048 frames
+    epoch: u32,
 }
 
 impl EventRing {
-    pub fn new() -> Result<EventRing> {
+    /// Create an event ring of `segments` segments, `erst_max` being the controller's ERST Max
+    pub fn new(segments: usize, erst_max: u16) -> Result<EventRing, XhciError> {
+        if segments == 0 || segments > MAX_SEGMENTS {
+            return Err(XhciError::InvalidState("invalid event ring segment count"));
+        }
+        if segments > erst_max as usize {
+            return Err(XhciError::InvalidState("more event ring segments than ERST Max"));
+        }
+
         let mut ring = EventRing {
-            ste: Dma::zeroed()?,
-            ring: Ring::new(false)?,
+            ste: Dma::zeroed().or(Err(XhciError::DmaAlloc))?,
+            segments: Vec::with_capacity(segments),
+            segment: 0,
+            dequeue: 0,
+            cycle: true,
+            pending: VecDeque::new(),
+            epoch: 0,
         };
 
-        ring.ste.address.write(ring.ring.trbs.physical() as u64);
-        ring.ste.size.write(ring.ring.trbs.len() as u16);
+        for i in 0..segments {
+            let trbs: Dma<[Trb; SEGMENT_SIZE]> = Dma::zeroed().or(Err(XhciError::DmaAlloc))?;
+            ring.ste[i].address.write(trbs.physical() as u64);
+            ring.ste[i].size.write(SEGMENT_SIZE as u16);
+            ring.segments.push(trbs);
+        }
 
         Ok(ring)
     }
 
-    pub fn next(&mut self) -> &mut Trb {
-        self.ring.next().0
+    /// Return to the start of the first segment, where the controller writes events after ERSTBA is written
+    pub fn reset(&mut self) {
+        for segment in self.segments.iter_mut() {
+            for trb in segment.iter_mut() {
+                trb.set(0, 0, 0);
+            }
+        }
+        self.segment = 0;
+        self.dequeue = 0;
+        self.cycle = true;
+        self.pending.clear();
+    }
+
+    /// Number of segments, for ERSTSZ
+    pub fn erstsz(&self) -> u32 {
+        self.segments.len() as u32
+    }
+
+    /// Return the next event if the controller has written one, advancing the dequeue pointer past it
+    pub fn next(&mut self) -> Option<&Trb> {
+        let segment = self.segment;
+        let i = self.dequeue;
+        if self.segments[segment][i].cycle() != self.cycle {
+            return None;
+        }
+
+        // Segments are filled in segment table order, the cycle state toggles after the last one
+        self.dequeue += 1;
+        if self.dequeue >= SEGMENT_SIZE {
+            self.dequeue = 0;
+            self.segment += 1;
+            if self.segment >= self.segments.len() {
+                self.segment = 0;
+                self.cycle = !self.cycle;
+            }
+        }
+
+        Some(&self.segments[segment][i])
+    }
+
+    /// Return the next event, starting with the ones skipped by `wait_for`
+    pub fn next_event(&mut self) -> Option<XhciEvent> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+
+        let fields = self.next().map(|event| (event.data.read(), event.status.read(), event.control.read()));
+        fields.map(|(data, status, control)| {
+            self.observe(control);
+            XhciEvent::new(data, status, control)
+        })
+    }
+
+    /// Keep track of the events that change the state of the ring itself, as soon as they are read
+    fn observe(&mut self, control: u32) {
+        if ((control >> 10) & 0x3F) as u8 == TrbType::MfindexWrap as u8 {
+            self.epoch = self.epoch.wrapping_add(1);
+        }
+    }
+
+    /// Frame number at which MFINDEX last wrapped, counting every wrap seen since the ring was created
+    ///
+    /// MFINDEX counts 2^14 microframes, which is 2^11 frames of 1 ms.
+    pub fn current_frame(&self) -> u64 {
+        (self.epoch as u64) << 11
+    }
+
+    /// Wait for the next event of type `kind`, queueing any others, and return its data, status and control fields
+    pub fn wait_for(&mut self, kind: TrbType) -> Result<(u64, u32, u32), XhciError> {
+        let kind = kind as u8;
+        self.wait_for_match(|_, _, control| ((control >> 10) & 0x3F) as u8 == kind)
+    }
+
+    /// Wait for the next event whose data, status and control fields satisfy `matches`, queueing any others, and
+    /// return its fields
+    pub fn wait_for_match<F: Fn(u64, u32, u32) -> bool>(&mut self, matches: F) -> Result<(u64, u32, u32), XhciError> {
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        loop {
+            let fields = self.next().map(|event| (event.data.read(), event.status.read(), event.control.read()));
+            if let Some((data, status, control)) = fields {
+                self.observe(control);
+                if matches(data, status, control) {
+                    return Ok((data, status, control));
+                } else {
+                    self.pending.push_back(XhciEvent::new(data, status, control));
+                }
+            }
+
+            timeout.check()?;
+        }
+    }
+
+    /// Physical address of the dequeue pointer, for ERDP
+    pub fn erdp_phys(&self) -> u64 {
+        (self.segments[self.segment].physical() + self.dequeue * mem::size_of::<Trb>()) as u64
+    }
+
+    /// Physical address of the segment table, for ERSTBA
+    pub fn erstba(&self) -> u64 {
+        self.ste.physical() as u64
+    }
+
+    /// Tell the controller which events have been processed, and that the handler is no longer busy
+    pub fn advance_dequeue(&self, int: &mut InterrupterRegs) {
+        // The low bits of the segment index help the controller tell whether the ring is full
+        int.erdp.write(self.erdp_phys() | (self.segment as u64 & 0b111) | ERDP_EHB);
     }
 }
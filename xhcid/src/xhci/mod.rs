@@ -1,107 +1,126 @@
 use plain::Plain;
-use std::{mem, slice};
-use syscall::error::Result;
+use std::{cmp, mem, slice};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use syscall::io::{Dma, Io};
+use hub;
+use log::{self, LogLevel};
 use usb;
 
 mod capability;
 mod command;
 mod context;
+mod device;
 mod doorbell;
+mod error;
 mod event;
 mod operational;
 mod port;
 mod runtime;
 mod ring;
 mod scheme;
+mod stats;
+mod stream;
+mod timeout;
+mod transfer_ring;
 mod trb;
 
 use self::capability::CapabilityRegs;
+pub use self::capability::ProtocolCapability;
 use self::command::CommandRing;
-use self::context::{DeviceContextList, InputContext};
+use self::context::{EndpointContextBuilder, EndpointState, EpType, InputContext, ScratchpadBufferArray, SlotState};
+use self::device::{DeviceList, DeviceStrings, InterruptBuffer, CONTROL_BUFFER_SIZE};
 use self::doorbell::Doorbell;
+pub use self::event::{DeviceNotification, EventRing, XhciEvent};
+pub use self::error::XhciError;
 use self::operational::OperationalRegs;
+pub use self::operational::{DeviceNotificationTypes, DN_FUNCTION_WAKE, DN_LATENCY_TOLERANCE};
 use self::port::Port;
-use self::ring::Ring;
-use self::runtime::{RuntimeRegs, Interrupter};
-use self::trb::TransferKind;
-
-struct Device<'a> {
-    ring: &'a mut Ring,
-    cmd: &'a mut CommandRing,
-    db: &'a mut Doorbell,
-    int: &'a mut Interrupter,
+pub use self::port::{PortLinkState, PortSpeed};
+use self::runtime::{InterrupterRegs, RuntimeRegs};
+pub use self::stats::XhciStats;
+use self::stream::{StreamContextArray, StreamContextType};
+pub use self::timeout::Timeout;
+use self::transfer_ring::TransferRing;
+use self::trb::{CompletionCode, Trb, TrbType};
+pub use self::trb::TransferKind;
+
+/// How long to wait for the controller to respond before giving up, in milliseconds
+pub const TIMEOUT_MS: u64 = 1000;
+
+/// Number of segments to use for the primary event ring, if the controller supports that many
+const EVENT_RING_SEGMENTS: usize = 4;
+
+/// Microframes between now and the first microframe an isochronous transfer is scheduled for, to stay clear of
+/// the controller's isochronous scheduling threshold
+pub const MIN_SCHEDULING_LOOKAHEAD: u64 = 16;
+
+/// Language ID used for string descriptors, en-US
+pub const DEFAULT_LANG_ID: u16 = 0x0409;
+
+/// How long a transfer may stay queued before the watchdog in `Xhci::poll` gives up on it, for each kind of
+/// endpoint, or `None` to wait forever
+#[derive(Clone, Copy, Debug)]
+pub struct TransferTimeouts {
+    pub control: Option<Duration>,
+    pub isoch: Option<Duration>,
+    pub bulk: Option<Duration>,
+    pub interrupt: Option<Duration>,
 }
 
-impl<'a> Device<'a> {
-    fn get_desc<T>(&mut self, kind: usb::DescriptorKind, index: u8, desc: &mut Dma<T>) {
-        let len = mem::size_of::<T>();
-
-        {
-            let (cmd, cycle) = self.ring.next();
-            cmd.setup(
-                usb::Setup::get_descriptor(kind, index, 0, len as u16),
-                TransferKind::In, cycle
-            );
-        }
-
-        {
-            let (cmd, cycle) = self.ring.next();
-            cmd.data(desc.physical(), len as u16, true, cycle);
-        }
-
-        {
-            let (cmd, cycle) = self.ring.next();
-            cmd.status(false, cycle);
+impl TransferTimeouts {
+    fn get(&self, kind: usb::EndpointKind) -> Option<Duration> {
+        match kind {
+            usb::EndpointKind::Control => self.control,
+            usb::EndpointKind::Isoch => self.isoch,
+            usb::EndpointKind::Bulk => self.bulk,
+            usb::EndpointKind::Interrupt => self.interrupt,
         }
-
-        self.db.write(1);
-
-        {
-            let event = self.cmd.next_event();
-            while event.data.read() == 0 {
-                println!("  - Waiting for event");
-            }
-        }
-
-        self.int.erdp.write(self.cmd.erdp());
     }
+}
 
-    fn get_device(&mut self) -> Result<usb::DeviceDescriptor> {
-        let mut desc = Dma::<usb::DeviceDescriptor>::zeroed()?;
-        self.get_desc(
-            usb::DescriptorKind::Device,
-            0,
-            &mut desc
-        );
-        Ok(*desc)
+impl Default for TransferTimeouts {
+    fn default() -> TransferTimeouts {
+        // Interrupt IN endpoints, such as those of HID devices, only complete when the device has something to send
+        TransferTimeouts {
+            control: Some(Duration::from_secs(5)),
+            isoch: None,
+            bulk: Some(Duration::from_secs(5)),
+            interrupt: None,
+        }
     }
+}
 
-    fn get_config(&mut self, config: u8) -> Result<(usb::ConfigDescriptor, [u8; 4087])> {
-        let mut desc = Dma::<(usb::ConfigDescriptor, [u8; 4087])>::zeroed()?;
-        self.get_desc(
-            usb::DescriptorKind::Configuration,
-            config,
-            &mut desc
-        );
-        Ok(*desc)
-    }
+pub struct StopEndpointResult {
+    /// Dequeue pointer of the stopped ring, as saved by the controller in the output endpoint context
+    pub dequeue: u64,
+    /// Dequeue cycle state
+    pub dcs: bool,
+}
 
-    fn get_string(&mut self, index: u8) -> Result<String> {
-        let mut sdesc = Dma::<(u8, u8, [u16; 127])>::zeroed()?;
-        self.get_desc(
-            usb::DescriptorKind::String,
-            index,
-            &mut sdesc
-        );
+/// Registers of an interrupter with an event ring, saved across a system suspend
+#[derive(Clone, Copy, Debug)]
+pub struct InterrupterState {
+    pub index: usize,
+    pub iman: u32,
+    pub imod: u32,
+    pub erstsz: u32,
+    pub erstba: u64,
+    pub erdp: u64,
+}
 
-        let len = sdesc.0 as usize;
-        if len > 2 {
-            Ok(String::from_utf16(&sdesc.2[.. (len - 2)/2]).unwrap_or(String::new()))
-        } else {
-            Ok(String::new())
-        }
-    }
+/// Controller registers lost when the controller is powered off during a system suspend
+#[derive(Clone, Debug)]
+pub struct XhciSavedState {
+    pub usb_cmd: u32,
+    pub dn_ctrl: u32,
+    pub dcbaap: u64,
+    pub config: u32,
+    pub interrupters: Vec<InterrupterState>,
+    /// PORTSC of each root hub port, of which the power and link state are used
+    pub ports: Vec<u32>,
 }
 
 pub struct Xhci {
@@ -110,65 +129,130 @@ pub struct Xhci {
     ports: &'static mut [Port],
     dbs: &'static mut [Doorbell],
     run: &'static mut RuntimeRegs,
-    dev_ctx: DeviceContextList,
+    devices: DeviceList,
+    scratchpad: Option<ScratchpadBufferArray>,
     cmd: CommandRing,
+    /// Primary event ring, registered with interrupter 0
+    events: EventRing,
+    /// Size of each slot, endpoint and input control context, 32 or 64 bytes
+    context_size: usize,
+    stats: XhciStats,
+    /// Where device notifications are sent, if a driver has registered for them
+    notifications: Option<Sender<DeviceNotification>>,
+    /// Registers saved by `global_suspend`, until `global_resume`
+    suspended: Option<XhciSavedState>,
+    /// Called with the index of a root hub port, starting at 0, when it reports an over-current condition
+    overcurrent: Option<Box<Fn(usize)>>,
+    /// Called with the completion code and bytes transferred when the TD queued with a cookie completes
+    event_data_callbacks: BTreeMap<u64, Box<FnMut(u8, u32)>>,
+    transfer_timeouts: TransferTimeouts,
+    /// When the oldest transfer queued on an endpoint times out, keyed by slot and endpoint ID
+    transfer_deadlines: BTreeMap<(u8, u8), Instant>,
+    /// Called with the slot and endpoint ID of an endpoint whose transfers timed out
+    transfer_timeout_callback: Option<Box<Fn(u8, u8)>>,
+    /// Supported Protocol capabilities, which tell USB 2.0 ports from USB 3.x ones
+    protocols: Vec<ProtocolCapability>,
 }
 
 impl Xhci {
-    pub fn new(address: usize) -> Result<Xhci> {
+    /// Initialise the controller whose registers are mapped at `address`, with `size` bytes mapped
+    pub fn new(address: usize, size: usize) -> Result<Xhci, XhciError> {
+        validate_region(address, mem::size_of::<CapabilityRegs>(), address, size)?;
         let cap = unsafe { &mut *(address as *mut CapabilityRegs) };
-        println!("  - CAP {:X}", address);
+        debug!("  - CAP {:X}", address);
+
+        debug!("  - Version {:X}", cap.version());
 
         let op_base = address + cap.len.read() as usize;
+        validate_region(op_base, mem::size_of::<OperationalRegs>(), address, size)?;
         let op = unsafe { &mut *(op_base as *mut OperationalRegs) };
-        println!("  - OP {:X}", op_base);
+        debug!("  - OP {:X}", op_base);
 
         let max_slots;
         let max_ports;
+        let context_size;
 
         {
-            println!("  - Wait for ready");
+            debug!("  - Wait for ready");
             // Wait until controller is ready
-            while op.usb_sts.readf(1 << 11) {
-                println!("  - Waiting for XHCI ready");
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while op.is_controller_not_ready() {
+                timeout.check()?;
             }
 
-            println!("  - Stop");
-            // Set run/stop to 0
-            op.usb_cmd.writef(1, false);
+            // Take the controller from the BIOS if it is still using it
+            for (id, offset) in capability::walk_extended_caps(address, size, cap.hcc_params1.read()) {
+                if id == capability::CAP_ID_LEGACY {
+                    debug!("  - Legacy handoff at {:X}", offset);
+                    capability::legacy_handoff(address, size, offset)?;
+                }
+            }
 
-            println!("  - Wait for not running");
+            debug!("  - Stop");
+            op.set_run_stop(false);
+
+            debug!("  - Wait for not running");
             // Wait until controller not running
-            while ! op.usb_sts.readf(1) {
-                println!("  - Waiting for XHCI stopped");
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while ! op.halted() {
+                timeout.check()?;
             }
 
-            println!("  - Reset");
-            op.usb_cmd.writef(1 << 1, true);
-            while op.usb_sts.readf(1 << 1) {
-                println!("  - Waiting for XHCI reset");
+            debug!("  - Reset");
+            op.trigger_hcrst();
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while op.hcrst_pending() {
+                timeout.check()?;
+            }
+            while op.is_controller_not_ready() {
+                timeout.check()?;
             }
 
-            println!("  - Read max slots");
+            debug!("  - Read max slots");
             // Read maximum slots and ports
             let hcs_params1 = cap.hcs_params1.read();
             max_slots = (hcs_params1 & 0xFF) as u8;
             max_ports = ((hcs_params1 & 0xFF000000) >> 24) as u8;
 
-            println!("  - Max Slots: {}, Max Ports {}", max_slots, max_ports);
+            debug!("  - Max Slots: {}, Max Ports {}, Max Interrupters {}", max_slots, max_ports, cap.max_interrupters());
+
+            let hccparams1 = cap.hccparams1();
+            debug!("  - {:?}", hccparams1);
+            context_size = if hccparams1.csz { 64 } else { 32 };
+        }
+
+        let protocols = capability::parse_protocol_capabilities(address, size, cap.hcc_params1.read());
+        for protocol in protocols.iter() {
+            debug!("  - USB {:X}.{:02X}: ports {} to {}", protocol.major, protocol.minor, protocol.port_offset, protocol.port_offset as u16 + protocol.port_count as u16 - 1);
         }
 
         let port_base = op_base + 0x400;
+        validate_region(port_base, max_ports as usize * mem::size_of::<Port>(), address, size)?;
         let ports = unsafe { slice::from_raw_parts_mut(port_base as *mut Port, max_ports as usize) };
-        println!("  - PORT {:X}", port_base);
+        debug!("  - PORT {:X}", port_base);
 
         let db_base = address + cap.db_offset.read() as usize;
+        validate_region(db_base, 256 * mem::size_of::<Doorbell>(), address, size)?;
         let dbs = unsafe { slice::from_raw_parts_mut(db_base as *mut Doorbell, 256) };
-        println!("  - DOORBELL {:X}", db_base);
+        debug!("  - DOORBELL {:X}", db_base);
 
         let run_base = address + cap.rts_offset.read() as usize;
+        // Only the interrupters the controller implements have to be mapped
+        validate_region(run_base, 0x20 + cap.max_interrupters() as usize * mem::size_of::<InterrupterRegs>(), address, size)?;
         let run = unsafe { &mut *(run_base as *mut RuntimeRegs) };
-        println!("  - RUNTIME {:X}", run_base);
+        debug!("  - RUNTIME {:X}", run_base);
+
+        let erst_max = cap.erst_max();
+        let event_segments = cmp::min(erst_max as usize, EVENT_RING_SEGMENTS);
+        debug!("  - Event Ring Segments: {}", event_segments);
+
+        let max_scratchpad_bufs = cap.max_scratchpad_bufs();
+        debug!("  - Max Scratchpad Buffers: {}", max_scratchpad_bufs);
+        let scratchpad = if max_scratchpad_bufs > 0 {
+            Some(ScratchpadBufferArray::new(max_scratchpad_bufs)?)
+        } else {
+            None
+        };
 
         let mut xhci = Xhci {
             cap: cap,
@@ -176,189 +260,338 @@ impl Xhci {
             ports: ports,
             dbs: dbs,
             run: run,
-            dev_ctx: DeviceContextList::new(max_slots)?,
+            devices: DeviceList::new(max_slots, context_size)?,
+            scratchpad: scratchpad,
             cmd: CommandRing::new()?,
+            events: EventRing::new(event_segments, erst_max)?,
+            context_size: context_size,
+            stats: XhciStats::default(),
+            notifications: None,
+            suspended: None,
+            overcurrent: None,
+            event_data_callbacks: BTreeMap::new(),
+            transfer_timeouts: TransferTimeouts::default(),
+            transfer_deadlines: BTreeMap::new(),
+            transfer_timeout_callback: None,
+            protocols: protocols,
         };
 
-        xhci.init(max_slots);
+        xhci.init(max_slots)?;
 
         Ok(xhci)
     }
 
-    pub fn init(&mut self, max_slots: u8) {
+    pub fn init(&mut self, max_slots: u8) -> Result<(), XhciError> {
         // Set enabled slots
-        println!("  - Set enabled slots to {}", max_slots);
+        debug!("  - Set enabled slots to {}", max_slots);
         self.op.config.write(max_slots as u32);
-        println!("  - Enabled Slots: {}", self.op.config.read() & 0xFF);
+        debug!("  - Enabled Slots: {}", self.op.config.read() & 0xFF);
+
+        // Set scratchpad buffer array pointer
+        if let Some(ref scratchpad) = self.scratchpad {
+            let register = scratchpad.register();
+            debug!("  - Write scratchpad buffer array: {:X}", register);
+            self.devices.dcbaa[0] = register;
+        }
 
         // Set device context address array pointer
-        let dcbaap = self.dev_ctx.dcbaap();
-        println!("  - Write DCBAAP: {:X}", dcbaap);
+        let dcbaap = self.devices.dcbaap();
+        debug!("  - Write DCBAAP: {:X}", dcbaap);
         self.op.dcbaap.write(dcbaap as u64);
 
         // Set command ring control register
         let crcr = self.cmd.crcr();
-        println!("  - Write CRCR: {:X}", crcr);
+        debug!("  - Write CRCR: {:X}", crcr);
         self.op.crcr.write(crcr as u64);
 
         // Set event ring segment table registers
-        println!("  - Interrupter 0: {:X}", self.run.ints.as_ptr() as usize);
+        debug!("  - Interrupter 0: {:X}", self.run.ints.as_ptr() as usize);
         {
-            let erstz = 1;
-            println!("  - Write ERSTZ: {}", erstz);
+            let erstz = self.events.erstsz();
+            debug!("  - Write ERSTZ: {}", erstz);
             self.run.ints[0].erstsz.write(erstz);
 
-            let erdp = self.cmd.erdp();
-            println!("  - Write ERDP: {:X}", erdp);
+            let erdp = self.events.erdp_phys();
+            debug!("  - Write ERDP: {:X}", erdp);
             self.run.ints[0].erdp.write(erdp as u64);
 
-            let erstba = self.cmd.erstba();
-            println!("  - Write ERSTBA: {:X}", erstba);
+            let erstba = self.events.erstba();
+            debug!("  - Write ERSTBA: {:X}", erstba);
             self.run.ints[0].erstba.write(erstba as u64);
 
-            println!("  - Enable interrupts");
-            self.run.ints[0].iman.writef(1 << 1, true);
+            debug!("  - Enable interrupts");
+            self.run.ints[0].enable_interrupts();
         }
+        self.op.set_interrupter_enable(true);
+
+        // Function Wake and Latency Tolerance notifications are handled, other types stay suppressed
+        self.enable_notifications(DN_FUNCTION_WAKE | DN_LATENCY_TOLERANCE);
 
-        // Set run/stop to 1
-        println!("  - Start");
-        self.op.usb_cmd.writef(1, true);
+        debug!("  - Start");
+        self.op.set_run_stop(true);
 
         // Wait until controller is running
-        println!("  - Wait for running");
-        while self.op.usb_sts.readf(1) {
-            println!("  - Waiting for XHCI running");
+        debug!("  - Wait for running");
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        while self.op.halted() {
+            timeout.check()?;
+        }
+
+        // With port power control, ports may start powered off and would never report a connection
+        if self.cap.hccparams1().ppc {
+            for i in 0..self.ports.len() {
+                if ! self.ports[i].flags().contains(port::PORT_PP) {
+                    debug!("  - Power on port {}", i);
+                    if let Err(err) = self.ports[i].power_on() {
+                        warn!("  - Port {}: {}", i, err);
+                    }
+                }
+            }
+        }
+
+        // Check that the command ring is processed
+        debug!("  - No-op command");
+        let code = self.no_op()?;
+        debug!("  - No-op completed: {:?}", code);
+        if code != CompletionCode::Success {
+            return Err(XhciError::BadCompletionCode(code as u8));
         }
 
-        // Ring command doorbell
-        println!("  - Ring doorbell");
-        self.dbs[0].write(0);
+        debug!("  - XHCI initialized");
 
-        println!("  - XHCI initialized");
+        Ok(())
     }
 
-    pub fn probe(&mut self) -> Result<()> {
-        for (i, port) in self.ports.iter().enumerate() {
-            let data = port.read();
-            let state = port.state();
-            let speed = port.speed();
-            let flags = port.flags();
-            println!("   + XHCI Port {}: {:X}, State {}, Speed {}, Flags {:?}", i, data, state, speed, flags);
+    /// Choose which Device Notification types the controller reports as Device Notification Events
+    pub fn enable_notifications(&mut self, types: DeviceNotificationTypes) {
+        debug!("  - Write DNCTRL: {:X}", types.bits());
+        self.op.set_device_notification(types.bits());
+    }
 
-            if flags.contains(port::PORT_CCS) {
-                //TODO: Link TRB when running to the end of the ring buffer
+    /// Submit the command TRB written by `f` and wait for its completion, returning the slot ID of the event
+    fn command<F: FnOnce(&mut Trb, bool)>(&mut self, f: F) -> Result<u8, XhciError> {
+        let (code, slot) = self.submit_command(f)?;
+        if code == CompletionCode::Success as u8 {
+            Ok(slot)
+        } else {
+            Err(XhciError::BadCompletionCode(code))
+        }
+    }
 
-                println!("    - Enable slot");
+    /// Submit the command TRB written by `f` and wait for its completion, returning the completion code and the
+    /// slot ID of the event
+    fn submit_command<F: FnOnce(&mut Trb, bool)>(&mut self, f: F) -> Result<(u8, u8), XhciError> {
+        {
+            let (cmd, cycle) = self.cmd.next_cmd()?;
+            f(cmd, cycle);
+        }
+        let trb_phys = self.cmd.last_cmd_ptr();
+
+        self.dbs[0].ring_command();
+
+        // Completions of commands queued earlier without waiting are left for `handle_events`
+        let (data, status, control) = match self.events.wait_for_match(|data, _, control| {
+            ((control >> 10) & 0x3F) as u8 == TrbType::CommandCompletion as u8 && data == trb_phys
+        }) {
+            Ok(fields) => fields,
+            Err(XhciError::ControllerTimeout) => {
+                self.abort_command_ring()?;
+                return Err(XhciError::ControllerTimeout);
+            },
+            Err(err) => return Err(err),
+        };
+        self.events.advance_dequeue(&mut self.run.ints[0]);
+        self.cmd.set_dequeue_ptr(data);
 
-                let slot;
-                {
-                    let (cmd, cycle, event) = self.cmd.next();
+        Ok(((status >> 24) as u8, (control >> 24) as u8))
+    }
 
-                    cmd.enable_slot(0, cycle);
+    /// Handle events until every queued command has completed, or `timeout_ms` milliseconds have passed
+    pub fn wait_for_commands(&mut self, timeout_ms: u64) -> Result<(), XhciError> {
+        let timeout = Timeout::from_millis(timeout_ms);
+        loop {
+            self.handle_events()?;
+            if self.cmd.pending_count() == 0 {
+                return Ok(());
+            }
+            timeout.check()?;
+        }
+    }
 
-                    self.dbs[0].write(0);
+    /// Abort the command ring after a command times out, moving the enqueue pointer back to where the controller
+    /// stopped so that new commands can be queued
+    pub fn abort_command_ring(&mut self) -> Result<(), XhciError> {
+        if ! self.op.command_ring_running() {
+            return Ok(());
+        }
 
-                    while event.data.read() == 0 {
-                        println!("    - Waiting for event");
-                    }
-                    slot = (event.control.read() >> 24) as u8;
+        self.op.abort_command_ring();
 
-                    cmd.reserved(false);
-                    event.reserved(false);
-                }
+        {
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while self.op.command_ring_running() {
+                timeout.check()?;
+            }
+        }
 
-                self.run.ints[0].erdp.write(self.cmd.erdp());
+        // The aborted command completes first, followed by the Command Ring Stopped event
+        loop {
+            let (data, status, _) = self.events.wait_for(TrbType::CommandCompletion)?;
+            self.events.advance_dequeue(&mut self.run.ints[0]);
 
-                println!("    - Slot {}", slot);
+            let code = (status >> 24) as u8;
+            if code == CompletionCode::CommandRingStopped as u8 {
+                self.cmd.set_enqueue_ptr(data);
+                return Ok(());
+            }
+            self.cmd.set_dequeue_ptr(data);
+        }
+    }
 
-                let mut ring = Ring::new(true)?;
+    /// Set up a secondary interrupter with its own event ring, which is returned so that a caller can poll it
+    /// separately from the primary interrupter
+    pub fn allocate_interrupter(&mut self, index: u8) -> Result<EventRing, XhciError> {
+        if index == 0 {
+            return Err(XhciError::InvalidState("interrupter 0 is the primary interrupter"));
+        }
 
-                let mut input = Dma::<InputContext>::zeroed()?;
-                {
-                    input.add_context.write(1 << 1 | 1);
+        let max_interrupters = self.cap.max_interrupters();
+        if index as u16 >= max_interrupters {
+            return Err(XhciError::InvalidState("interrupter not implemented by the controller"));
+        }
 
-                    input.device.slot.a.write((1 << 27) | (speed << 20));
-                    input.device.slot.b.write(((i as u32 + 1) & 0xFF) << 16);
+        let erst_max = self.cap.erst_max();
+        let events = EventRing::new(cmp::min(erst_max as usize, EVENT_RING_SEGMENTS), erst_max)?;
 
-                    input.device.endpoints[0].b.write(4096 << 16 | 4 << 3 | 3 << 1);
-                    let tr = ring.register();
-                    input.device.endpoints[0].trh.write((tr >> 32) as u32);
-                    input.device.endpoints[0].trl.write(tr as u32);
-                }
+        let int = &mut self.run.interrupters_mut(max_interrupters)[index as usize];
+        int.erstsz.write(events.erstsz());
+        int.erdp.write(events.erdp_phys());
+        int.erstba.write(events.erstba());
+        int.enable_interrupts();
 
-                {
-                    let (cmd, cycle, event) = self.cmd.next();
+        Ok(events)
+    }
 
-                    cmd.address_device(slot, input.physical(), cycle);
+    /// Set up the event ring of a secondary interrupter again after `restore_state` reset the controller, sending
+    /// the ring back to its first segment
+    pub fn restore_interrupter(&mut self, index: u8, events: &mut EventRing) -> Result<(), XhciError> {
+        if index == 0 {
+            return Err(XhciError::InvalidState("interrupter 0 is the primary interrupter"));
+        }
 
-                    self.dbs[0].write(0);
+        let max_interrupters = self.cap.max_interrupters();
+        let int = self.run.interrupters_mut(max_interrupters).get_mut(index as usize)
+            .ok_or(XhciError::InvalidState("interrupter not implemented by the controller"))?;
+        events.reset();
+        int.erstsz.write(events.erstsz());
+        int.erdp.write(events.erdp_phys());
+        int.erstba.write(events.erstba());
+        int.enable_interrupts();
+        Ok(())
+    }
 
-                    while event.data.read() == 0 {
-                        println!("    - Waiting for event");
-                    }
+    /// Tell the controller how far the event ring of a secondary interrupter has been consumed
+    pub fn advance_interrupter_dequeue(&mut self, index: u8, events: &EventRing) -> Result<(), XhciError> {
+        let max_interrupters = self.cap.max_interrupters();
+        let int = self.run.interrupters_mut(max_interrupters).get_mut(index as usize)
+            .ok_or(XhciError::InvalidState("interrupter not implemented by the controller"))?;
+        events.advance_dequeue(int);
+        Ok(())
+    }
 
-                    cmd.reserved(false);
-                    event.reserved(false);
-                }
+    /// Save the registers needed to bring the controller back after a system suspend
+    pub fn save_state(&self) -> XhciSavedState {
+        let mut interrupters = Vec::new();
+        for (index, int) in self.run.interrupters(self.cap.max_interrupters()).iter().enumerate() {
+            // Interrupters without an event ring have nothing to restore
+            let erstsz = int.erstsz.read();
+            if erstsz > 0 {
+                interrupters.push(InterrupterState {
+                    index: index,
+                    iman: int.iman.read(),
+                    imod: int.imod.read(),
+                    erstsz: erstsz,
+                    erstba: int.erstba.read(),
+                    erdp: int.erdp.read(),
+                });
+            }
+        }
 
-                self.run.ints[0].erdp.write(self.cmd.erdp());
+        XhciSavedState {
+            usb_cmd: self.op.usb_cmd.read(),
+            dn_ctrl: self.op.dn_ctrl.read(),
+            dcbaap: self.op.dcbaap.read(),
+            config: self.op.config.read(),
+            interrupters: interrupters,
+            ports: self.ports.iter().map(|port| port.read()).collect(),
+        }
+    }
 
-                let mut dev = Device {
-                    ring: &mut ring,
-                    cmd: &mut self.cmd,
-                    db: &mut self.dbs[slot as usize],
-                    int: &mut self.run.ints[0],
-                };
+    /// Suspend the links of all active ports and halt the controller, before the system enters S3 or S4
+    pub fn global_suspend(&mut self) -> Result<(), XhciError> {
+        if self.suspended.is_some() {
+            return Err(XhciError::InvalidState("controller already suspended"));
+        }
 
-                println!("    - Get descriptor");
+        for slot in self.devices.remote_wakeup_slots() {
+            let port = self.port_for_slot(slot)?;
+            debug!("  - Arm port {} for remote wakeup of slot {}", port - 1, slot);
+            self.ports[port as usize - 1].arm_wakeup();
+        }
 
-                let ddesc = dev.get_device()?;
-                println!("      {:?}", ddesc);
+        for i in 0..self.ports.len() {
+            if ! self.ports[i].flags().contains(port::PORT_PED) {
+                continue;
+            }
+            match self.ports[i].link_state() {
+                port::PortLinkState::U0 | port::PortLinkState::U1 | port::PortLinkState::U2 => {
+                    debug!("  - Suspend port {}", i);
+                    self.ports[i].suspend()?;
+                },
+                _ => (),
+            }
+        }
 
-                if ddesc.manufacturer_str > 0 {
-                    println!("        Manufacturer: {}", dev.get_string(ddesc.manufacturer_str)?);
-                }
+        // Saved while running, so that Run/Stop is set again on resume
+        let state = self.save_state();
 
-                if ddesc.product_str > 0 {
-                    println!("        Product: {}", dev.get_string(ddesc.product_str)?);
-                }
+        debug!("  - Stop");
+        self.op.set_run_stop(false);
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        while ! self.op.halted() {
+            timeout.check()?;
+        }
 
-                if ddesc.serial_str > 0 {
-                    println!("        Serial: {}", dev.get_string(ddesc.serial_str)?);
-                }
+        self.suspended = Some(state);
+        Ok(())
+    }
 
-                for config in 0..ddesc.configurations {
-                    let (cdesc, data) = dev.get_config(config)?;
-                    println!("        {}: {:?}", config, cdesc);
+    /// Start the controller again after `global_suspend` and resume the links of the suspended ports
+    ///
+    /// If the controller lost power while the system was suspended, it is reset with `restore_state` instead.
+    pub fn global_resume(&mut self) -> Result<(), XhciError> {
+        let state = self.suspended.take().ok_or(XhciError::InvalidState("controller not suspended"))?;
 
-                    if cdesc.configuration_str > 0 {
-                        println!("          Name: {}", dev.get_string(cdesc.configuration_str)?);
-                    }
+        // Registers are cleared if power was lost
+        if self.op.dcbaap.read() != state.dcbaap {
+            debug!("  - Controller lost its state, reset");
+            return self.restore_state(state);
+        }
 
-                    if cdesc.total_length as usize > mem::size_of::<usb::ConfigDescriptor>() {
-                        let len = cdesc.total_length as usize - mem::size_of::<usb::ConfigDescriptor>();
-
-                        let mut i = 0;
-                        for interface in 0..cdesc.interfaces {
-                            let mut idesc = usb::InterfaceDescriptor::default();
-                            if i < len && i < data.len() && idesc.copy_from_bytes(&data[i..len]).is_ok() {
-                                i += mem::size_of_val(&idesc);
-                                println!("          {}: {:?}", interface, idesc);
-
-                                if idesc.interface_str > 0 {
-                                    println!("            Name: {}", dev.get_string(idesc.interface_str)?);
-                                }
-
-                                for endpoint in 0..idesc.endpoints {
-                                    let mut edesc = usb::EndpointDescriptor::default();
-                                    if i < len && i < data.len() && edesc.copy_from_bytes(&data[i..len]).is_ok() {
-                                        i += mem::size_of_val(&edesc);
-                                        println!("            {}: {:?}", endpoint, edesc);
-                                    }
-                                }
-                            }
-                        }
-                    }
+        debug!("  - Start");
+        self.op.set_run_stop(true);
+        {
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while self.op.halted() {
+                timeout.check()?;
+            }
+        }
+
+        for i in 0..self.ports.len() {
+            if self.ports[i].link_state() == port::PortLinkState::U3 {
+                debug!("  - Resume port {}", i);
+                if let Err(err) = self.ports[i].resume() {
+                    warn!("  - Port {}: {}", i, err);
                 }
             }
         }
@@ -366,13 +599,1587 @@ impl Xhci {
         Ok(())
     }
 
-    pub fn irq(&mut self) -> bool {
-        if self.run.ints[0].iman.readf(1) {
-            println!("XHCI Interrupt");
-            self.run.ints[0].iman.writef(1, true);
-            true
-        } else {
-            false
+    /// Reset the controller after a system resume and write back the registers saved by `save_state`
+    ///
+    /// Every slot is disabled by the reset, so the devices are enumerated again once the controller is running.
+    /// Event rings from `allocate_interrupter` have to be handed back with `restore_interrupter`.
+    pub fn restore_state(&mut self, state: XhciSavedState) -> Result<(), XhciError> {
+        debug!("  - Stop");
+        self.op.set_run_stop(false);
+        {
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while ! self.op.halted() {
+                timeout.check()?;
+            }
+        }
+
+        debug!("  - Reset");
+        self.op.trigger_hcrst();
+        {
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while self.op.hcrst_pending() || self.op.is_controller_not_ready() {
+                timeout.check()?;
+            }
+        }
+
+        self.op.config.write(state.config);
+        self.op.dcbaap.write(state.dcbaap);
+        self.op.dn_ctrl.write(state.dn_ctrl);
+
+        // The controller restarts the command ring at the enqueue pointer, with nothing left to complete
+        self.op.crcr.write(self.cmd.crcr());
+        self.cmd.reset_dequeue_ptr();
+
+        for int_state in state.interrupters.iter() {
+            let int = &mut self.run.ints[int_state.index];
+            int.imod.write(int_state.imod);
+            // The event rings of secondary interrupters belong to their callers, which set them up again with
+            // `restore_interrupter`
+            if int_state.index != 0 {
+                continue;
+            }
+
+            // Writing ERSTBA sends the controller back to the start of the primary event ring
+            self.events.reset();
+            int.erstsz.write(int_state.erstsz);
+            int.erstba.write(int_state.erstba);
+            int.erdp.write(self.events.erdp_phys());
+            // Interrupt Pending is write-one-to-clear and is not restored
+            int.iman.write(int_state.iman & !1);
+        }
+
+        // Ports come out of reset powered unless the controller has port power control
+        for (regs, &portsc) in self.ports.iter_mut().zip(state.ports.iter()) {
+            let was_powered = port::PortFlags::from_bits_truncate(portsc).contains(port::PORT_PP);
+            if was_powered != regs.flags().contains(port::PORT_PP) {
+                regs.set_power(was_powered);
+            }
+        }
+
+        // Run/Stop is set last, once everything else is in place
+        let run = state.usb_cmd & 1 != 0;
+        self.op.usb_cmd.write(state.usb_cmd & !1);
+        if run {
+            debug!("  - Start");
+            self.op.set_run_stop(true);
+            let timeout = Timeout::from_millis(TIMEOUT_MS);
+            while self.op.halted() {
+                timeout.check()?;
+            }
+        }
+
+        for slot in 1..self.devices.contexts.len() as u16 + 1 {
+            let slot = slot as u8;
+            if self.devices.is_used(slot) {
+                self.devices.free_slot(slot);
+            }
+        }
+
+        if run {
+            let devices = self.enumerate_all_ports()?;
+            info!("   + XHCI {} devices enumerated after resume", devices.len());
+        }
+
+        Ok(())
+    }
+
+    /// Version of the xHCI specification the controller implements, in BCD
+    pub fn spec_version(&self) -> u16 {
+        self.cap.version()
+    }
+
+    /// Log every readable capability, operational, port and primary interrupter register
+    pub fn dump_registers(&self) {
+        info!("XHCI registers");
+        info!("  CAPLENGTH: {:X}", self.cap.len.read());
+        info!("  HCIVERSION: {:X}", self.cap.version());
+        info!("  HCSPARAMS1: {:X}", self.cap.hcs_params1.read());
+        info!("  HCSPARAMS2: {:X}", self.cap.hcs_params2.read());
+        info!("  HCSPARAMS3: {:X}", self.cap.hcs_params3.read());
+        info!("  HCCPARAMS1: {:X}", self.cap.hcc_params1.read());
+        info!("  HCCPARAMS2: {:X}", self.cap.hcc_params2.read());
+        info!("  DBOFF: {:X}", self.cap.db_offset.read());
+        info!("  RTSOFF: {:X}", self.cap.rts_offset.read());
+
+        info!("  USBCMD: {:X}", self.op.usb_cmd.read());
+        info!("  USBSTS: {:X}", self.op.usb_sts.read());
+        info!("  PAGESIZE: {:X}", self.op.page_size.read());
+        info!("  DNCTRL: {:X}", self.op.dn_ctrl.read());
+        info!("  CRCR: {:X}", self.op.crcr.read());
+        info!("  DCBAAP: {:X}", self.op.dcbaap.read());
+        info!("  CONFIG: {:X}", self.op.config.read());
+
+        for (i, port) in self.ports.iter().enumerate() {
+            info!("  Port {}: PORTSC {:X}, PORTPMSC {:X}, PORTLI {:X}, PORTHLPMC {:X}",
+                  i, port.portsc.read(), port.portpmsc.read(), port.portli.read(), port.porthlpmc.read());
+        }
+
+        let int = &self.run.ints[0];
+        info!("  Interrupter 0: IMAN {:X}, IMOD {:X}, ERSTSZ {:X}, ERSTBA {:X}, ERDP {:X}",
+              int.iman.read(), int.imod.read(), int.erstsz.read(), int.erstba.read(), int.erdp.read());
+    }
+
+    /// Log the fields of the output slot context of a slot
+    pub fn dump_slot_context(&self, slot: u8) -> Result<(), XhciError> {
+        let context = self.devices.context(slot)?.slot();
+        info!("Slot {}: state {:?}, address {}, root hub port {}, route string {:X}, speed {:?}",
+              slot, context.state(), context.usb_address(), context.root_hub_port(), context.route_string(), context.speed());
+        info!("  context entries {}, ports {}, interrupter {}, TT hub slot {}, TT port {}",
+              context.context_entries(), context.num_ports(), context.interrupter_target(), context.tt_hub_slot(), context.tt_port());
+        Ok(())
+    }
+
+    /// Log the fields of the output endpoint context of an endpoint ID of a slot
+    pub fn dump_endpoint_context(&self, slot: u8, endpoint_id: u8) -> Result<(), XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+
+        let context = self.devices.context(slot)?.endpoint(endpoint_id as usize - 1);
+        info!("Slot {} endpoint {}: state {:?}, type {:?}, max packet size {}, max burst {}, interval {}, error count {}",
+              slot, endpoint_id, context.state(), context.ep_type(), context.max_packet_size(), context.max_burst_size(),
+              context.interval(), context.error_count());
+        info!("  dequeue {:X}, DCS {}", context.dequeue(), context.dcs());
+        Ok(())
+    }
+
+    /// Submit a No-Op command, which checks that the command and event rings are working
+    pub fn no_op(&mut self) -> Result<CompletionCode, XhciError> {
+        let (code, _) = self.submit_command(|cmd, cycle| cmd.no_op_cmd(cycle))?;
+        CompletionCode::try_from(code).or(Err(XhciError::BadCompletionCode(code)))
+    }
+
+    /// Set up the slot and EP0 contexts of a slot from an input context and send SET_ADDRESS to the device,
+    /// returning the USB address it was given
+    pub fn address_device(&mut self, slot: u8, input_ctx_phys: u64) -> Result<u8, XhciError> {
+        self.command(|cmd, cycle| cmd.address_device(slot, input_ctx_phys as usize, false, cycle))?;
+        Ok(self.devices.context(slot)?.slot().usb_address())
+    }
+
+    /// Address a slot with `address_device` and remember the USB address the device was given
+    pub fn assign_usb_address(&mut self, slot: u8, input_ctx_phys: u64) -> Result<u8, XhciError> {
+        let address = self.address_device(slot, input_ctx_phys)?;
+        self.devices.set_usb_address(slot, address);
+        Ok(address)
+    }
+
+    /// Set up the slot and EP0 contexts of a slot without sending SET_ADDRESS, leaving the slot in the Default
+    /// state so that the device descriptor can be read at address 0 before `address_device`
+    pub fn address_device_bsr(&mut self, slot: u8, input_ctx_phys: u64) -> Result<u8, XhciError> {
+        self.command(|cmd, cycle| cmd.address_device(slot, input_ctx_phys as usize, true, cycle))?;
+        Ok(self.devices.context(slot)?.slot().usb_address())
+    }
+
+    /// Disable a slot after its device has gone away, releasing its controller resources
+    pub fn disable_slot(&mut self, slot: u8) -> Result<(), XhciError> {
+        self.command(|cmd, cycle| cmd.disable_slot(slot, cycle))?;
+        self.devices.free_slot(slot);
+
+        let endpoints: Vec<(u8, u8)> = self.transfer_deadlines.keys().filter(|&&(deadline_slot, _)| deadline_slot == slot).cloned().collect();
+        for endpoint in endpoints {
+            self.transfer_deadlines.remove(&endpoint);
+        }
+        Ok(())
+    }
+
+    /// Return the device of a slot to the Default state without a USB bus reset, disabling all its endpoints except EP0
+    ///
+    /// The device must then be addressed and enumerated again from the start.
+    pub fn reset_device(&mut self, slot: u8) -> Result<(), XhciError> {
+        match self.devices.context(slot)?.slot().state() {
+            SlotState::Addressed | SlotState::Configured => (),
+            _ => return Err(XhciError::InvalidState("slot not addressed")),
+        }
+
+        self.command(|cmd, cycle| cmd.reset_device(slot, cycle))?;
+        self.devices.reset_slot(slot);
+        Ok(())
+    }
+
+    /// Add, drop or change the endpoints of an addressed or configured slot as described by `input_ctx`
+    pub fn configure_endpoint(&mut self, slot: u8, input_ctx: &InputContext) -> Result<(), XhciError> {
+        match self.devices.context(slot)?.slot().state() {
+            SlotState::Addressed | SlotState::Configured => (),
+            _ => return Err(XhciError::InvalidState("slot not addressed")),
+        }
+
+        let input = input_ctx.physical();
+        self.command(|cmd, cycle| cmd.configure_endpoint(slot, input, false, cycle))?;
+        Ok(())
+    }
+
+    /// Drop all endpoints of a slot except the default control endpoint, returning it to the Addressed state
+    pub fn deconfigure_endpoints(&mut self, slot: u8) -> Result<(), XhciError> {
+        self.command(|cmd, cycle| cmd.configure_endpoint(slot, 0, true, cycle))?;
+        Ok(())
+    }
+
+    /// Update the slot context and default control endpoint, such as EP0's max packet size once it is known
+    pub fn evaluate_context(&mut self, slot: u8, input_ctx: &InputContext) -> Result<(), XhciError> {
+        // Only the slot context (A0) and EP0 (A1) are evaluated, and nothing may be dropped
+        if input_ctx.control().add_context.read() & !0b11 != 0 || input_ctx.control().drop_context.read() != 0 {
+            return Err(XhciError::InvalidState("evaluate context only applies to the slot and EP0"));
+        }
+
+        let input = input_ctx.physical();
+        self.command(|cmd, cycle| cmd.evaluate_context(slot, input, cycle))?;
+        Ok(())
+    }
+
+    /// Ask the controller to renegotiate the bandwidth of the periodic endpoints of a slot
+    ///
+    /// A Bandwidth Error is returned as `BadCompletionCode` like any other failure, so that the caller can
+    /// reduce the requirements of its endpoints and retry.
+    pub fn negotiate_bandwidth(&mut self, slot: u8) -> Result<(), XhciError> {
+        if ! self.cap.hccparams1().bnc {
+            return Err(XhciError::InvalidState("BNC not supported"));
+        }
+
+        self.command(|cmd, cycle| cmd.negotiate_bandwidth(slot, cycle))?;
+        Ok(())
+    }
+
+    /// Set the Best Effort Latency Tolerance, in nanoseconds, reported by the controller for a slot's device
+    ///
+    /// The value applies to the whole controller, the BELT field holds it in microseconds, so it is rounded up to
+    /// the next microsecond and may be at most 4095 us.
+    pub fn set_latency_tolerance_value(&mut self, slot: u8, belt: u32) -> Result<(), XhciError> {
+        if ! self.cap.hccparams1().ltc {
+            return Err(XhciError::InvalidState("LTC not supported"));
+        }
+        if ! self.devices.is_used(slot) {
+            return Err(XhciError::InvalidState("slot not enabled"));
+        }
+
+        let belt = (belt as u64 + 999) / 1000;
+        if belt > 0xFFF {
+            return Err(XhciError::InvalidState("latency tolerance larger than 4095 us"));
+        }
+        let belt = belt as u16;
+        self.command(|cmd, cycle| cmd.set_latency_tolerance_value(belt, cycle))?;
+        Ok(())
+    }
+
+    /// Send a USB 3.x header packet on a root hub port, starting at 1, for link testing
+    ///
+    /// The low 5 bits of `header_info[0]` are replaced by `packet_type`.
+    pub fn force_header(&mut self, port_id: u8, packet_type: u8, header_info: [u32; 3]) -> Result<(), XhciError> {
+        if port_id == 0 || port_id as usize > self.ports.len() {
+            return Err(XhciError::PortError(port_id as usize));
+        }
+        // The port type comes from the protocol capability, as nothing has to be attached to the port
+        if ! self.port_protocol(port_id as usize - 1).map(|protocol| protocol.major >= 3).unwrap_or(false) {
+            return Err(XhciError::InvalidState("force header requires a USB 3.x port"));
         }
+
+        self.command(|cmd, cycle| cmd.force_header(port_id, packet_type, header_info, cycle))?;
+        Ok(())
+    }
+
+    /// Put the link of a USB 2.0 root hub port, starting at 0, into the L1 power state
+    pub fn suspend_port(&mut self, port: usize) -> Result<(), XhciError> {
+        if port >= self.ports.len() {
+            return Err(XhciError::PortError(port));
+        }
+        if ! self.port_protocol(port).map(|protocol| protocol.supports_l1()).unwrap_or(false) {
+            return Err(XhciError::InvalidState("port does not support L1"));
+        }
+
+        let slot = self.devices.slot_for_port(port as u8 + 1).ok_or(XhciError::InvalidState("no device on the port"))?;
+        let besl = self.devices.lpm_besl(slot).ok_or(XhciError::InvalidState("device does not support LPM"))?;
+
+        self.ports[port].enable_lpm(slot, besl)
+    }
+
+    /// Bring the link of a USB 2.0 root hub port, starting at 0, back from L1 to L0
+    pub fn resume_port(&mut self, port: usize) -> Result<(), XhciError> {
+        self.ports.get_mut(port).ok_or(XhciError::PortError(port))?.exit_lpm()
+    }
+
+    /// Move the link of a root hub port, starting at 0, to `state`, such as U3 to selectively suspend it or U0 to
+    /// resume it
+    pub fn set_port_link_state(&mut self, port: usize, state: PortLinkState) -> Result<(), XhciError> {
+        self.ports.get_mut(port).ok_or(XhciError::PortError(port))?.set_link_state(state)
+    }
+
+    /// Allow the link between a USB 3.x device on a root hub port and the controller to enter U1 and U2 when idle,
+    /// with the inactivity timeouts written to PORTPMSC
+    pub fn enable_u1_u2(&mut self, slot: u8, u1_timeout: u8, u2_timeout: u8) -> Result<(), XhciError> {
+        let (speed, route_string, port_id) = {
+            let context = self.devices.context(slot)?.slot();
+            (context.speed(), context.route_string(), context.root_hub_port())
+        };
+        match speed {
+            Some(PortSpeed::SuperSpeed) | Some(PortSpeed::SuperSpeedPlus) => (),
+            _ => return Err(XhciError::InvalidState("U1 and U2 require a USB 3.x device")),
+        }
+        // The timeouts of a hub's downstream ports are set with hub requests instead
+        if route_string != 0 {
+            return Err(XhciError::InvalidState("U1 and U2 are only supported on root hub ports"));
+        }
+        if port_id == 0 || port_id as usize > self.ports.len() {
+            return Err(XhciError::PortError(port_id as usize));
+        }
+
+        let (dev_u1, dev_u2) = self.fetch_bos_descriptor(slot)?.u1_u2_exit_latency()
+            .ok_or(XhciError::InvalidState("device has no SuperSpeed capability"))?;
+
+        // The device is directly attached, so the exit latency of the path is the larger of the root port's and
+        // the device's
+        let u1 = cmp::max(self.cap.u1_exit_latency(), dev_u1);
+        let u2 = cmp::max(self.cap.u2_exit_latency(), dev_u2);
+        self.set_sel(slot, u1, u1, u2, u2)?;
+
+        if u1_timeout > 0 {
+            self.control_transfer(slot, usb::Setup::set_feature(usb::FEATURE_U1_ENABLE), TransferKind::NoData, None)?;
+        }
+        if u2_timeout > 0 {
+            self.control_transfer(slot, usb::Setup::set_feature(usb::FEATURE_U2_ENABLE), TransferKind::NoData, None)?;
+        }
+
+        self.ports[port_id as usize - 1].set_u1_u2_timeouts(u1_timeout, u2_timeout);
+        Ok(())
+    }
+
+    /// Tell a USB 3.x device the system exit latencies (SEL) and path exit latencies (PEL) of U1 and U2 with
+    /// SET_SEL, which the device needs before U1 and U2 are used. All latencies are in microseconds.
+    pub fn set_sel(&mut self, slot: u8, u1_sel: u8, u1_pel: u8, u2_sel: u16, u2_pel: u16) -> Result<(), XhciError> {
+        let mut data = [
+            u1_sel,
+            u1_pel,
+            u2_sel as u8, (u2_sel >> 8) as u8,
+            u2_pel as u8, (u2_pel >> 8) as u8,
+        ];
+        // The completion code of the status stage is checked by the control transfer
+        self.control_transfer(slot, usb::Setup::set_sel(), TransferKind::Out, Some(&mut data[..]))?;
+        Ok(())
+    }
+
+    /// Read the bandwidth available to devices of `speed` on each port of a hub, or of the root hub if
+    /// `hub_slot` is 0, as percentages indexed by port number minus one
+    pub fn get_port_bandwidth(&mut self, hub_slot: u8, speed: PortSpeed) -> Result<Vec<u8>, XhciError> {
+        let context = Dma::<[u8; 256]>::zeroed().or(Err(XhciError::DmaAlloc))?;
+
+        let physical = context.physical();
+        self.command(|cmd, cycle| cmd.get_port_bandwidth(physical, speed as u8, hub_slot, cycle))?;
+
+        // The first byte is reserved, the rest is one byte per downstream port
+        let ports = if hub_slot == 0 { self.ports.len() } else { context.len() - 1 };
+        Ok(context[1..ports + 1].to_vec())
+    }
+
+    /// Whether a root hub port, starting at 1, has enough bandwidth left for an isochronous endpoint of a device of
+    /// `speed` moving `esit_payload` bytes per service interval, to be checked before the endpoint is configured
+    pub fn check_iso_bandwidth(&mut self, port: u8, speed: PortSpeed, esit_payload: u32) -> Result<bool, XhciError> {
+        if port == 0 || port as usize > self.ports.len() {
+            return Err(XhciError::PortError(port as usize));
+        }
+
+        let available = self.get_port_bandwidth(0, speed)?[port as usize - 1];
+        let required = bandwidth_percentage(speed, esit_payload);
+        debug!("XHCI Port {}: {}% of bandwidth required, {}% available", port - 1, required, available);
+        Ok(required <= available as u32)
+    }
+
+    /// Notify the controller that TRBs have been queued on an endpoint of a slot
+    pub fn ring_endpoint_doorbell(&mut self, slot: u8, endpoint_id: u8) {
+        self.ring_stream_doorbell(slot, endpoint_id, 0);
+    }
+
+    /// Notify the controller that TRBs have been queued on a stream of an endpoint, 0 for endpoints without streams
+    pub fn ring_stream_doorbell(&mut self, slot: u8, endpoint_id: u8, stream_id: u16) {
+        self.dbs[slot as usize].ring_endpoint(endpoint_id, stream_id);
+        self.arm_transfer_deadline(slot, endpoint_id);
+    }
+
+    /// Start the watchdog of an endpoint, unless it is already waiting for an older transfer
+    fn arm_transfer_deadline(&mut self, slot: u8, endpoint_id: u8) {
+        if self.transfer_deadlines.contains_key(&(slot, endpoint_id)) {
+            return;
+        }
+
+        let kind = match self.devices.context(slot).map(|context| context.endpoint(endpoint_id as usize - 1).ep_type().kind()) {
+            Ok(Some(kind)) => kind,
+            _ => return,
+        };
+        if let Some(timeout) = self.transfer_timeouts.get(kind) {
+            self.transfer_deadlines.insert((slot, endpoint_id), Instant::now() + timeout);
+        }
+    }
+
+    /// Start the watchdog of an endpoint over after one of its transfers completed, for the next transfer queued
+    /// on it if there is one
+    fn restart_transfer_deadline(&mut self, slot: u8, endpoint_id: u8) {
+        self.transfer_deadlines.remove(&(slot, endpoint_id));
+        if self.devices.queued_trbs(slot, endpoint_id) > 0 {
+            self.arm_transfer_deadline(slot, endpoint_id);
+        }
+    }
+
+    /// Change how long transfers on endpoints of a kind may take, `None` disabling the watchdog for them
+    pub fn set_transfer_timeout(&mut self, kind: usb::EndpointKind, timeout: Option<Duration>) {
+        match kind {
+            usb::EndpointKind::Control => self.transfer_timeouts.control = timeout,
+            usb::EndpointKind::Isoch => self.transfer_timeouts.isoch = timeout,
+            usb::EndpointKind::Bulk => self.transfer_timeouts.bulk = timeout,
+            usb::EndpointKind::Interrupt => self.transfer_timeouts.interrupt = timeout,
+        }
+    }
+
+    /// Register `f` to be called with the slot and endpoint ID of an endpoint whose transfers timed out
+    pub fn on_transfer_timeout<F: Fn(u8, u8) + 'static>(&mut self, f: F) {
+        self.transfer_timeout_callback = Some(Box::new(f));
+    }
+
+    /// Give up on the transfers of endpoints whose deadline has passed
+    fn check_transfer_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(u8, u8)> = self.transfer_deadlines.iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&endpoint, _)| endpoint)
+            .collect();
+
+        for (slot, endpoint_id) in expired {
+            self.transfer_deadlines.remove(&(slot, endpoint_id));
+            warn!("XHCI transfer on slot {} endpoint {} timed out", slot, endpoint_id);
+            if let Err(err) = self.abort_transfers(slot, endpoint_id) {
+                warn!("XHCI failed to abort transfers on slot {} endpoint {}: {}", slot, endpoint_id, err);
+            }
+            if let Some(ref f) = self.transfer_timeout_callback {
+                f(slot, endpoint_id);
+            }
+        }
+    }
+
+    /// Drop every transfer queued on an endpoint, leaving it stopped with its dequeue pointer at the enqueue pointer
+    fn abort_transfers(&mut self, slot: u8, endpoint_id: u8) -> Result<(), XhciError> {
+        let state = self.devices.context(slot)?.endpoint(endpoint_id as usize - 1).state();
+        match state {
+            // Resetting a halted endpoint leaves it stopped
+            EndpointState::Halted => self.reset_endpoint(slot, endpoint_id, false)?,
+            EndpointState::Stopped => (),
+            _ => {
+                self.stop_endpoint(slot, endpoint_id, false)?;
+            },
+        }
+
+        let enqueue = self.devices.ring(slot, endpoint_id)?.register();
+        self.set_tr_dequeue_pointer(slot, endpoint_id, 0, enqueue & !0xF, enqueue & 1 == 1, 0)?;
+        self.devices.ring(slot, endpoint_id)?.move_dequeue_ptr(enqueue);
+        Ok(())
+    }
+
+    /// Queue a bulk OUT transfer on an endpoint and ring its doorbell, completion is reported as a Transfer Event
+    pub fn submit_bulk_out<T>(&mut self, slot: u8, endpoint_id: u8, data: &Dma<T>, len: usize) -> Result<(), XhciError> {
+        self.devices.ring(slot, endpoint_id)?.submit_bulk_out(data, len, false, true)?;
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Queue a bulk OUT transfer on an endpoint and ring its doorbell, ending it with a zero length packet if it
+    /// fills its last packet, so that the device can tell where it ends
+    pub fn submit_bulk_out_zlp<T>(&mut self, slot: u8, endpoint_id: u8, data: &Dma<T>, len: usize) -> Result<(), XhciError> {
+        if len > mem::size_of::<T>() {
+            return Err(XhciError::InvalidState("transfer larger than buffer"));
+        }
+
+        let max_packet_size = self.devices.context(slot)?.endpoint(endpoint_id as usize - 1).max_packet_size() as usize;
+        {
+            let ring = self.devices.ring(slot, endpoint_id)?;
+            if len == 0 {
+                ring.submit_bulk_out(data, 0, false, true)?;
+            } else if max_packet_size > 0 && len % max_packet_size == 0 {
+                ring.submit_bulk_out_zlp(data.physical() as u64, len)?;
+            } else {
+                ring.submit_scatter_gather(&[(data.physical() as u64, len)], true)?;
+            }
+        }
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Queue a bulk IN transfer on an endpoint and ring its doorbell, completion is reported as a Transfer Event
+    pub fn submit_bulk_in<T>(&mut self, slot: u8, endpoint_id: u8, data: &mut Dma<T>, len: usize) -> Result<(), XhciError> {
+        self.devices.ring(slot, endpoint_id)?.submit_bulk_in(data, len, false, true)?;
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Replace the transfer ring of a SuperSpeed bulk endpoint by `num_streams` streams, with IDs starting at 1
+    pub fn configure_streams(&mut self, slot: u8, endpoint_id: u8, num_streams: u16) -> Result<(), XhciError> {
+        let max_psa_size = self.cap.hccparams1().max_psa_size;
+        if max_psa_size == 0 {
+            return Err(XhciError::InvalidState("controller does not support streams"));
+        }
+        match self.slot_speed(slot)? {
+            PortSpeed::SuperSpeed | PortSpeed::SuperSpeedPlus => (),
+            _ => return Err(XhciError::InvalidState("streams require a SuperSpeed device")),
+        }
+
+        let mut array = StreamContextArray::new(num_streams)?;
+        if array.size() > 1 << (max_psa_size + 1) {
+            return Err(XhciError::InvalidState("more streams than MaxPSASize"));
+        }
+
+        let mut rings = Vec::with_capacity(num_streams as usize);
+        for stream_id in 1..num_streams + 1 {
+            let ring = TransferRing::new()?;
+            array.contexts[stream_id as usize].set_dequeue(ring.register(), StreamContextType::Primary);
+            rings.push((stream_id, ring));
+        }
+
+        let mut input = InputContext::new(self.context_size)?;
+        input.add_slot();
+        input.drop_endpoint(endpoint_id);
+        input.add_endpoint(endpoint_id);
+        {
+            let context = self.devices.context(slot)?;
+            input.copy_slot_context(context.slot());
+            input.copy_endpoint_context(endpoint_id as usize - 1, context.endpoint(endpoint_id as usize - 1));
+        }
+        input.endpoint_mut(endpoint_id as usize - 1).set_streams(array.max_primary_streams(), array.physical() as u64);
+        self.configure_endpoint(slot, &input)?;
+
+        self.devices.set_streams(slot, endpoint_id, array, rings);
+        Ok(())
+    }
+
+    /// Queue a bulk transfer on a stream of an endpoint and ring its doorbell, the direction is taken from the endpoint ID
+    pub fn submit_stream_bulk<T>(&mut self, slot: u8, endpoint_id: u8, stream_id: u16, data: &mut Dma<T>, len: usize) -> Result<(), XhciError> {
+        {
+            let ring = self.devices.stream_ring(slot, endpoint_id, stream_id)?;
+            // Odd endpoint IDs above 1 are IN endpoints
+            if endpoint_id & 1 == 1 {
+                ring.submit_bulk_in(data, len, false, true)?;
+            } else {
+                ring.submit_bulk_out(data, len, false, true)?;
+            }
+        }
+        self.ring_stream_doorbell(slot, endpoint_id, stream_id);
+        Ok(())
+    }
+
+    /// Queue a transfer spanning `segments` on an endpoint, ending in an Event Data TRB with `cookie`, and ring its
+    /// doorbell. `f` is called with the completion code and bytes transferred once the whole transfer completes.
+    pub fn submit_with_event_data<F: FnMut(u8, u32) + 'static>(&mut self, slot: u8, endpoint_id: u8, segments: &[(u64, usize)], cookie: u64, f: F) -> Result<(), XhciError> {
+        if self.event_data_callbacks.contains_key(&cookie) {
+            return Err(XhciError::InvalidState("event data cookie already in use"));
+        }
+
+        self.devices.ring(slot, endpoint_id)?.submit_with_event_data(segments, cookie)?;
+        self.event_data_callbacks.insert(cookie, Box::new(f));
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Cancel a transfer queued with `submit_with_event_data` that has not completed yet, returning whether it was
+    /// found. The endpoint is stopped while its ring is changed, and restarted with the other transfers still queued.
+    pub fn cancel_transfer(&mut self, slot: u8, endpoint_id: u8, cookie: u64) -> Result<bool, XhciError> {
+        // The callback is removed once the transfer completes
+        if ! self.event_data_callbacks.contains_key(&cookie) {
+            return Ok(false);
+        }
+
+        let (dequeue, dcs) = match self.stop_endpoint(slot, endpoint_id, false) {
+            Ok(stopped) => (stopped.dequeue, stopped.dcs),
+            // A halted or already stopped endpoint cannot be stopped again, but its dequeue pointer is just as current
+            Err(XhciError::BadCompletionCode(code)) if code == CompletionCode::ContextState as u8 => {
+                (self.get_endpoint_dequeue_ptr(slot, endpoint_id)?, self.endpoint_dcs(slot, endpoint_id)?)
+            },
+            Err(err) => return Err(err),
+        };
+
+        // Transfers that completed just before the endpoint stopped must not be cancelled
+        self.handle_events()?;
+        if ! self.event_data_callbacks.contains_key(&cookie) {
+            self.ring_endpoint_doorbell(slot, endpoint_id);
+            return Ok(false);
+        }
+
+        let cancelled = self.devices.ring(slot, endpoint_id)?.cancel_td(dequeue, dcs, cookie);
+        if let Some(ref td) = cancelled {
+            // The controller may have cached the TRBs at its dequeue pointer, so it is moved past them
+            if td.at_dequeue {
+                self.set_tr_dequeue_pointer(slot, endpoint_id, 0, td.next, td.next_cycle, 0)?;
+                self.devices.ring(slot, endpoint_id)?.move_dequeue_ptr(td.next);
+            }
+            self.event_data_callbacks.remove(&cookie);
+        }
+
+        // Ringing the doorbell restarts a stopped endpoint
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(cancelled.is_some())
+    }
+
+    /// Queue an interrupt transfer on an endpoint, keeping `buf` so that it can be resubmitted after it completes
+    pub fn submit_interrupt_transfer(&mut self, slot: u8, endpoint_id: u8, buf: InterruptBuffer, len: usize) -> Result<(), XhciError> {
+        self.devices.ring(slot, endpoint_id)?.submit_interrupt(&buf, len)?;
+        self.devices.set_interrupt_buffer(slot, endpoint_id, buf, len);
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Queue the previous interrupt transfer of an endpoint again, reusing its buffer
+    pub fn resubmit_interrupt(&mut self, slot: u8, endpoint_id: u8) -> Result<(), XhciError> {
+        self.devices.resubmit_interrupt(slot, endpoint_id)?;
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Data received by the last completed interrupt transfer of an endpoint
+    pub fn interrupt_data(&self, slot: u8, endpoint_id: u8) -> Result<&[u8], XhciError> {
+        self.devices.interrupt_buffer(slot, endpoint_id)
+    }
+
+    /// Monotonic microframe number, from MFINDEX and the MFINDEX Wrap events handled so far
+    ///
+    /// A wrap that has happened but whose event has not been handled yet makes this go back by 2^14 microframes,
+    /// so events should be handled before scheduling from it.
+    pub fn current_microframe(&self) -> u64 {
+        (self.events.current_frame() << 3) | self.run.mfindex() as u64
+    }
+
+    /// First frame an isochronous transfer submitted now can safely start at
+    pub fn next_isoch_frame(&self) -> u64 {
+        (self.current_microframe() + MIN_SCHEDULING_LOOKAHEAD + 7) >> 3
+    }
+
+    /// Queue an isochronous transfer on an endpoint, starting at `frame` or as soon as possible if it is `None`
+    ///
+    /// `frame` is a frame number counted from `current_frame`, of which only the low 11 bits reach the controller.
+    pub fn submit_isoch_transfer<T>(&mut self, slot: u8, endpoint_id: u8, buf: &Dma<T>, len: usize, frame: Option<u64>) -> Result<(), XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+
+        let (max_packet_size, max_burst_size) = {
+            let endpoint = self.devices.context(slot)?.endpoint(endpoint_id as usize - 1);
+            (endpoint.max_packet_size(), endpoint.max_burst_size())
+        };
+
+        let frame_id = frame.map(|frame| (frame & 0x7FF) as u16);
+        self.devices.ring(slot, endpoint_id)?.submit_isoch(buf, len, max_packet_size, max_burst_size, frame_id)?;
+        self.ring_endpoint_doorbell(slot, endpoint_id);
+        Ok(())
+    }
+
+    /// Perform a control transfer on the default control endpoint of a slot, returning the number of bytes transferred
+    ///
+    /// The data stage goes through a bounce buffer, so `data` must hold at least `setup.length` bytes.
+    pub fn control_transfer(&mut self, slot: u8, setup: usb::Setup, direction: TransferKind, data: Option<&mut [u8]>) -> Result<usize, XhciError> {
+        let length = setup.length as usize;
+        if length > CONTROL_BUFFER_SIZE {
+            return Err(XhciError::InvalidState("control transfer larger than bounce buffer"));
+        }
+
+        let mut data = data;
+        if data.as_ref().map(|data| data.len()).unwrap_or(0) < length {
+            return Err(XhciError::InvalidState("control transfer larger than data"));
+        }
+
+        let buffer_phys = {
+            let buffer = self.devices.control_buffer(slot)?;
+            if direction == TransferKind::Out {
+                if let Some(ref data) = data {
+                    buffer[..length].copy_from_slice(&data[..length]);
+                }
+            }
+            buffer.physical()
+        };
+
+        {
+            let ring = self.devices.ring(slot, 1)?;
+            let trbs = if length > 0 { 3 } else { 2 };
+            if ring.free_slots() < trbs {
+                return Err(XhciError::RingFull);
+            }
+            let ring = &mut ring.ring;
+
+            {
+                let (cmd, cycle) = ring.next();
+                cmd.setup(setup, direction, cycle);
+            }
+
+            if length > 0 {
+                let (cmd, cycle) = ring.next();
+                cmd.data(buffer_phys, length as u16, direction == TransferKind::In, cycle);
+            }
+
+            {
+                // The status stage goes in the opposite direction of the data stage
+                let (cmd, cycle) = ring.next();
+                cmd.status(direction != TransferKind::In, cycle);
+            }
+        }
+
+        self.ring_endpoint_doorbell(slot, 1);
+
+        // A short data stage is reported before the status stage completes
+        let mut transferred = length;
+        loop {
+            let (trb, status, _) = {
+                // Transfers of other endpoints may complete meanwhile, their events are left for handle_events
+                let ring = self.devices.ring(slot, 1)?;
+                self.events.wait_for_match(|data, status, control| {
+                    match XhciEvent::new(data, status, control) {
+                        XhciEvent::Transfer { slot: event_slot, endpoint_id: 1, trb, .. } => event_slot == slot && ring.contains(trb),
+                        _ => false,
+                    }
+                })?
+            };
+            self.events.advance_dequeue(&mut self.run.ints[0]);
+            self.devices.ring(slot, 1)?.set_dequeue_ptr(trb);
+            // The event is consumed here rather than by handle_events, which would stop the watchdog
+            self.transfer_deadlines.remove(&(slot, 1));
+
+            let code = (status >> 24) as u8;
+            match CompletionCode::try_from(code) {
+                Ok(CompletionCode::ShortPacket) => {
+                    let residual = (status & 0xFFFFFF) as usize;
+                    transferred = length - cmp::min(residual, length);
+                },
+                Ok(CompletionCode::Success) => break,
+                _ => return Err(XhciError::BadCompletionCode(code)),
+            }
+        }
+
+        if direction == TransferKind::In {
+            if let Some(ref mut data) = data {
+                let buffer = self.devices.control_buffer(slot)?;
+                data[..transferred].copy_from_slice(&buffer[..transferred]);
+            }
+        }
+
+        Ok(transferred)
+    }
+
+    /// Read a descriptor of up to `length` bytes through the default control endpoint of a slot
+    fn get_desc(&mut self, slot: u8, kind: usb::DescriptorKind, index: u8, language: u16, length: u16) -> Result<Vec<u8>, XhciError> {
+        let mut data = vec![0; length as usize];
+        let count = self.control_transfer(
+            slot,
+            usb::Setup::get_descriptor(kind, index, language, length),
+            TransferKind::In,
+            Some(&mut data[..])
+        )?;
+        data.truncate(count);
+        Ok(data)
+    }
+
+    /// Read the device descriptor of an addressed slot and keep it in the device list
+    pub fn fetch_device_descriptor(&mut self, slot: u8) -> Result<usb::DeviceDescriptor, XhciError> {
+        let mut desc = usb::DeviceDescriptor::default();
+        {
+            let data = self.get_desc(slot, usb::DescriptorKind::Device, 0, 0, mem::size_of::<usb::DeviceDescriptor>() as u16)?;
+            desc.copy_from_bytes(&data).or(Err(XhciError::InvalidState("short device descriptor")))?;
+        }
+
+        self.devices.set_descriptor(slot, desc);
+        Ok(desc)
+    }
+
+    /// Read a full configuration descriptor, including its interface and endpoint descriptors
+    pub fn fetch_config_descriptor(&mut self, slot: u8, config_index: u8) -> Result<Vec<u8>, XhciError> {
+        // The header holds the total length of the descriptor set
+        let mut desc = usb::ConfigDescriptor::default();
+        {
+            let data = self.get_desc(slot, usb::DescriptorKind::Configuration, config_index, 0, mem::size_of::<usb::ConfigDescriptor>() as u16)?;
+            desc.copy_from_bytes(&data).or(Err(XhciError::InvalidState("short configuration descriptor")))?;
+        }
+
+        let total_length = desc.total_length;
+        if total_length as usize > CONTROL_BUFFER_SIZE {
+            return Err(XhciError::InvalidState("configuration descriptor larger than bounce buffer"));
+        }
+
+        self.get_desc(slot, usb::DescriptorKind::Configuration, config_index, 0, total_length)
+    }
+
+    /// Read the BOS descriptor of a slot, which lists the capabilities of USB 2.0 LPM and USB 3.x devices
+    pub fn fetch_bos_descriptor(&mut self, slot: u8) -> Result<usb::BosDescriptor, XhciError> {
+        // The header holds the total length of the descriptor and its capabilities
+        let mut header = usb::BosHeader::default();
+        {
+            let data = self.get_desc(slot, usb::DescriptorKind::Bos, 0, 0, mem::size_of::<usb::BosHeader>() as u16)?;
+            header.copy_from_bytes(&data).or(Err(XhciError::InvalidState("short BOS descriptor")))?;
+        }
+
+        let total_length = header.total_length;
+        if total_length as usize > CONTROL_BUFFER_SIZE {
+            return Err(XhciError::InvalidState("BOS descriptor larger than bounce buffer"));
+        }
+
+        let data = self.get_desc(slot, usb::DescriptorKind::Bos, 0, 0, total_length)?;
+        usb::BosDescriptor::parse(&data).ok_or(XhciError::InvalidState("invalid BOS descriptor"))
+    }
+
+    /// Select a configuration of a slot by its bConfigurationValue, enabling its non-control endpoints
+    pub fn set_configuration(&mut self, slot: u8, config_value: u8) -> Result<(), XhciError> {
+        self.control_transfer(slot, usb::Setup::set_configuration(config_value as u16), TransferKind::NoData, None)?;
+        Ok(())
+    }
+
+    /// Bring an addressed slot to the Configured state, using the endpoints of the first interface of its first
+    /// configuration, and enumerate the devices behind it if it is a hub
+    ///
+    /// `port` is the root hub port, starting at 0, through which the device is reached.
+    pub fn enumerate_device(&mut self, slot: u8, port: usize) -> Result<(), XhciError> {
+        let speed = self.slot_speed(slot)?;
+        let route_string = {
+            let context = self.devices.context(slot)?.slot();
+            if context.root_hub_port() as usize != port + 1 {
+                return Err(XhciError::InvalidState("slot is not attached to the port"));
+            }
+            context.route_string()
+        };
+
+        // The EP0 packet size assumed when addressing may differ from the one the device reports, which is
+        // in the first 8 bytes of the device descriptor so that it can be read with any packet size
+        {
+            let packet_size = {
+                let data = self.get_desc(slot, usb::DescriptorKind::Device, 0, 0, 8)?;
+                *data.get(7).ok_or(XhciError::InvalidState("short device descriptor"))?
+            };
+            let max_packet_size = if speed == PortSpeed::SuperSpeed || speed == PortSpeed::SuperSpeedPlus {
+                1u32 << cmp::min(packet_size, 15)
+            } else {
+                packet_size as u32
+            };
+
+            let current = self.devices.context(slot)?.endpoint(0).max_packet_size() as u32;
+            if max_packet_size != current {
+                debug!("    - EP0 max packet size {} instead of {}", max_packet_size, current);
+                let mut input = InputContext::new(self.context_size)?;
+                input.add_endpoint(1);
+                EndpointContextBuilder::new()
+                    .max_packet_size(max_packet_size as u16)
+                    .ep_type(EpType::Control)
+                    .build(input.endpoint_mut(0));
+                self.evaluate_context(slot, &input)?;
+            }
+        }
+
+        let ddesc = self.fetch_device_descriptor(slot)?;
+
+        let strings = DeviceStrings {
+            manufacturer: self.fetch_string_descriptor(slot, ddesc.manufacturer_str, DEFAULT_LANG_ID)?,
+            product: self.fetch_string_descriptor(slot, ddesc.product_str, DEFAULT_LANG_ID)?,
+            serial: self.fetch_string_descriptor(slot, ddesc.serial_str, DEFAULT_LANG_ID)?,
+        };
+        self.devices.set_strings(slot, strings);
+
+        // USB 2.0 devices report LPM support in their BOS descriptor, which devices before USB 2.01 do not have
+        if speed != PortSpeed::SuperSpeed && speed != PortSpeed::SuperSpeedPlus && ddesc.usb >= 0x0201 {
+            match self.fetch_bos_descriptor(slot) {
+                Ok(ref bos) if bos.supports_lpm() => self.devices.set_lpm_besl(slot, bos.baseline_besl().unwrap_or(0)),
+                Ok(_) => (),
+                Err(err) => debug!("    - Slot {}: no BOS descriptor: {}", slot, err),
+            }
+        }
+
+        let data = self.fetch_config_descriptor(slot, 0)?;
+        let mut cdesc = usb::ConfigDescriptor::default();
+        cdesc.copy_from_bytes(&data).or(Err(XhciError::InvalidState("short configuration descriptor")))?;
+
+        let endpoints = usb::parse_endpoints(&data);
+        if ! endpoints.is_empty() {
+            let mut input = InputContext::new(self.context_size)?;
+            let mut rings = Vec::new();
+            let mut entries = 1;
+
+            for &(edesc, companion) in endpoints.iter() {
+                let endpoint_id = edesc.number() * 2 + edesc.is_in() as u8;
+                if endpoint_id < 2 {
+                    continue;
+                }
+
+                let ep_type = EpType::new(edesc.kind(), edesc.is_in());
+                let periodic = ep_type.is_periodic();
+
+                let packet_size = edesc.packet_size();
+                let (max_burst, mult, max_esit_payload) = match (speed, companion) {
+                    // USB 3.x endpoints describe their bursts in the SuperSpeed Endpoint Companion descriptor
+                    (PortSpeed::SuperSpeed, Some(companion)) | (PortSpeed::SuperSpeedPlus, Some(companion)) => {
+                        let mult = if ep_type.is_isoch() { companion.mult() } else { 0 };
+                        let max_esit_payload = if periodic { companion.bytes_per_interval as u32 } else { 0 };
+                        (companion.max_burst, mult, max_esit_payload)
+                    },
+                    (PortSpeed::HighSpeed, _) if periodic => {
+                        let transactions = edesc.transactions();
+                        (transactions, 0, packet_size as u32 * (transactions as u32 + 1))
+                    },
+                    _ => (0, 0, if periodic { packet_size as u32 } else { 0 }),
+                };
+
+                // Only the bandwidth of root hub ports is checked, hub ports would need the hub's slot
+                if ep_type.is_isoch() && route_string == 0 && ! self.check_iso_bandwidth(port as u8 + 1, speed, max_esit_payload)? {
+                    warn!("    - Endpoint {}: not enough bandwidth, skipped", endpoint_id);
+                    continue;
+                }
+
+                let ring = TransferRing::new()?;
+                EndpointContextBuilder::new()
+                    .max_packet_size(packet_size)
+                    .ep_type(ep_type)
+                    .error_count(if ep_type.is_isoch() { 0 } else { 3 })
+                    .max_burst_size(max_burst)
+                    .mult(mult)
+                    .interval(context::descriptor_interval_to_context_interval(speed, ep_type, edesc.interval))
+                    .max_esit_payload(max_esit_payload)
+                    .average_trb_length(cmp::max(packet_size, 1))
+                    .dequeue(ring.register())
+                    .build(input.endpoint_mut(endpoint_id as usize - 1));
+                rings.push((endpoint_id, ring));
+
+                input.add_endpoint(endpoint_id);
+                entries = cmp::max(entries, endpoint_id);
+            }
+
+            input.add_slot();
+            input.copy_slot_context(self.devices.context(slot)?.slot());
+            input.slot_mut().set_context_entries(entries);
+
+            self.configure_endpoint(slot, &input)?;
+
+            // Only endpoints the controller enabled get a ring
+            for (endpoint_id, ring) in rings {
+                self.devices.add_ring(slot, endpoint_id, ring);
+            }
+        }
+
+        self.set_configuration(slot, cdesc.configuration_value)?;
+
+        if ddesc.class == hub::HUB_CLASS {
+            let hub = hub::UsbHub::new(self, slot)?;
+            let devices = hub.enumerate_ports(self)?;
+            debug!("    - Hub {}: {} devices enumerated", slot, devices.len());
+        }
+
+        Ok(())
+    }
+
+    /// Read a string descriptor in the language `lang_id`, returning an empty string for index 0
+    pub fn fetch_string_descriptor(&mut self, slot: u8, index: u8, lang_id: u16) -> Result<String, XhciError> {
+        // Index 0 is the list of supported languages, not a string
+        if index == 0 {
+            return Ok(String::new());
+        }
+
+        let len = {
+            let header = self.get_desc(slot, usb::DescriptorKind::String, index, lang_id, 2)?;
+            header.get(0).cloned().unwrap_or(0) as u16
+        };
+        if len <= 2 {
+            return Ok(String::new());
+        }
+
+        let data = self.get_desc(slot, usb::DescriptorKind::String, index, lang_id, len)?;
+        let utf16: Vec<u16> = data[2..].chunks(2).filter(|c| c.len() == 2).map(|c| (c[0] as u16) | (c[1] as u16) << 8).collect();
+        String::from_utf16(&utf16).or(Err(XhciError::InvalidState("invalid string descriptor")))
+    }
+
+    /// Recover a halted endpoint, keeping the transfer state if `preserve` is set, and resynchronize its ring
+    pub fn reset_endpoint(&mut self, slot: u8, endpoint_id: u8, preserve: bool) -> Result<(), XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+
+        self.command(|cmd, cycle| cmd.reset_endpoint(slot, endpoint_id, preserve, cycle))?;
+
+        let dequeue = self.get_endpoint_dequeue_ptr(slot, endpoint_id)?;
+        let dcs = self.endpoint_dcs(slot, endpoint_id)?;
+        self.set_tr_dequeue_pointer(slot, endpoint_id, 0, dequeue, dcs, 0)
+    }
+
+    /// Stop an endpoint ring so that its TRBs can be changed, optionally suspending it
+    pub fn stop_endpoint(&mut self, slot: u8, endpoint_id: u8, suspend: bool) -> Result<StopEndpointResult, XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+
+        self.command(|cmd, cycle| cmd.stop_endpoint(slot, endpoint_id, suspend, cycle))?;
+
+        Ok(StopEndpointResult {
+            dequeue: self.get_endpoint_dequeue_ptr(slot, endpoint_id)?,
+            dcs: self.endpoint_dcs(slot, endpoint_id)?,
+        })
+    }
+
+    /// Dequeue pointer of an endpoint ring as last written by the controller to the output endpoint context,
+    /// which is current once the endpoint is stopped or halted
+    pub fn get_endpoint_dequeue_ptr(&self, slot: u8, endpoint_id: u8) -> Result<u64, XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+        Ok(self.devices.context(slot)?.endpoint(endpoint_id as usize - 1).dequeue())
+    }
+
+    /// Dequeue Cycle State of the output endpoint context, to go with `get_endpoint_dequeue_ptr`
+    fn endpoint_dcs(&self, slot: u8, endpoint_id: u8) -> Result<bool, XhciError> {
+        Ok(self.devices.context(slot)?.endpoint(endpoint_id as usize - 1).dcs())
+    }
+
+    /// Move the controller's dequeue pointer for an endpoint ring, or the ring of stream `stream_id` if the endpoint
+    /// uses streams, which must be stopped or halted. `sct` is the Stream Context Type of the new ring, and both
+    /// are 0 for endpoints without streams.
+    pub fn set_tr_dequeue_pointer(&mut self, slot: u8, endpoint_id: u8, stream_id: u16, dequeue: u64, dcs: bool, sct: u8) -> Result<(), XhciError> {
+        if endpoint_id == 0 || endpoint_id > 31 {
+            return Err(XhciError::InvalidState("invalid endpoint ID"));
+        }
+        if dequeue & 0xF != 0 {
+            return Err(XhciError::InvalidState("dequeue pointer not aligned"));
+        }
+
+        self.command(|cmd, cycle| cmd.set_tr_dequeue_pointer(slot, endpoint_id, stream_id, dequeue, dcs, sct, cycle))?;
+        Ok(())
+    }
+
+    /// Handle any pending events, to be called from a driver thread or after an interrupt
+    pub fn poll(&mut self) -> Result<(), XhciError> {
+        if self.op.event_interrupt() {
+            self.op.clear_event_interrupt();
+        }
+
+        self.handle_events()?;
+        self.check_transfer_deadlines();
+
+        self.run.ints[0].clear_pending();
+
+        Ok(())
+    }
+
+    /// Set the most verbose level of messages that are printed
+    pub fn set_log_level(level: LogLevel) {
+        log::set_level(level);
+    }
+
+    /// Limit the interrupt rate, with `interval_250ns` the minimum interval between interrupts in 250 ns units
+    pub fn set_interrupt_moderation(&mut self, interval_250ns: u16) {
+        self.run.set_moderation(interval_250ns);
+    }
+
+    /// Drain the event ring, handling port status changes, and return the events that were found
+    pub fn handle_events(&mut self) -> Result<Vec<XhciEvent>, XhciError> {
+        let mut events = Vec::new();
+        while let Some(event) = self.events.next_event() {
+            events.push(event);
+        }
+        self.events.advance_dequeue(&mut self.run.ints[0]);
+
+        for event in events.iter() {
+            self.stats.record(event);
+            match *event {
+                XhciEvent::CommandCompletion { trb, .. } => self.cmd.set_dequeue_ptr(trb),
+                XhciEvent::PortStatusChange { port } => {
+                    // The events are already off the ring, so an error on one port must not drop the ones after it
+                    if let Err(err) = self.handle_port_event(port) {
+                        warn!("XHCI port {} event failed: {}", port, err);
+                    }
+                },
+                XhciEvent::Transfer { slot, endpoint_id, trb, .. } => {
+                    // The TRB tells which stream completed on endpoints with streams, and some errors are reported
+                    // without a TRB
+                    if trb != 0 {
+                        if let Some(ring) = self.devices.ring_containing(slot, endpoint_id, trb) {
+                            ring.set_dequeue_ptr(trb);
+                        }
+                    }
+                    self.restart_transfer_deadline(slot, endpoint_id);
+                },
+                XhciEvent::EventData { slot, endpoint_id, code, transferred, cookie } => {
+                    if let Ok(ring) = self.devices.ring(slot, endpoint_id) {
+                        ring.complete_event_data(cookie);
+                    }
+                    self.restart_transfer_deadline(slot, endpoint_id);
+                    match self.event_data_callbacks.remove(&cookie) {
+                        Some(mut f) => f(code, transferred),
+                        None => debug!("XHCI event data with unknown cookie {:X}", cookie),
+                    }
+                },
+                XhciEvent::DeviceNotification { notification, .. } => {
+                    if let DeviceNotification::FunctionWake { slot, interface_number } = notification {
+                        if let Err(err) = self.handle_function_wake(slot, interface_number) {
+                            warn!("XHCI function wake of slot {} interface {} failed: {}", slot, interface_number, err);
+                        }
+                    }
+                    self.send_device_notification(notification);
+                },
+                _ => (),
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Allow a device to signal remote wakeup while it is suspended, and arm its root hub port for it on
+    /// `global_suspend`
+    pub fn enable_remote_wakeup(&mut self, slot: u8) -> Result<(), XhciError> {
+        let setup = match self.slot_speed(slot)? {
+            // Remote wakeup of USB 3.x devices is enabled for each function, here the one of the first interface
+            PortSpeed::SuperSpeed | PortSpeed::SuperSpeedPlus => {
+                usb::Setup::set_interface_feature(usb::FEATURE_FUNCTION_SUSPEND, 0, usb::FUNCTION_SUSPEND_REMOTE_WAKE)
+            },
+            _ => usb::Setup::set_feature(usb::FEATURE_DEVICE_REMOTE_WAKEUP),
+        };
+        self.control_transfer(slot, setup, TransferKind::NoData, None)?;
+        self.devices.set_remote_wakeup(slot);
+        Ok(())
+    }
+
+    /// Resume the link of a device that sent a Function Wake notification if it is suspended, and take the
+    /// function out of Function Suspend
+    pub fn handle_function_wake(&mut self, slot: u8, interface_number: u8) -> Result<(), XhciError> {
+        let (route_string, port_id) = {
+            let context = self.devices.context(slot)?.slot();
+            (context.route_string(), context.root_hub_port())
+        };
+        // The links of devices behind hubs are resumed with hub requests instead
+        if route_string != 0 {
+            return Err(XhciError::InvalidState("function wake is only supported on root hub ports"));
+        }
+        if port_id == 0 || port_id as usize > self.ports.len() {
+            return Err(XhciError::PortError(port_id as usize));
+        }
+
+        let i = port_id as usize - 1;
+        if self.ports[i].link_state() == port::PortLinkState::U3 {
+            self.ports[i].exit_u3()?;
+            loop {
+                let (data, _, _) = self.events.wait_for(TrbType::PortStatusChange)?;
+                let event_port = (data >> 24) as u8;
+                if event_port == port_id {
+                    break;
+                }
+                if let Err(err) = self.handle_port_event(event_port) {
+                    warn!("XHCI port {} event failed: {}", event_port, err);
+                }
+            }
+            self.ports[i].clear_change_bits();
+            if self.ports[i].link_state() != port::PortLinkState::U0 {
+                return Err(XhciError::PortError(i));
+            }
+        }
+
+        // Clearing the suspend options of the function resumes it
+        let setup = usb::Setup::set_interface_feature(usb::FEATURE_FUNCTION_SUSPEND, interface_number, 0);
+        self.control_transfer(slot, setup, TransferKind::NoData, None)?;
+        Ok(())
+    }
+
+    /// Send device notifications, such as function wake, to `sender` as they are handled
+    pub fn register_notifications(&mut self, sender: Sender<DeviceNotification>) {
+        self.notifications = Some(sender);
+    }
+
+    /// Pass a device notification on to the registered receiver, dropping it if there is none
+    fn send_device_notification(&mut self, notification: DeviceNotification) {
+        let disconnected = match self.notifications {
+            Some(ref sender) => sender.send(notification).is_err(),
+            None => {
+                debug!("XHCI unhandled device notification: {:?}", notification);
+                false
+            },
+        };
+        if disconnected {
+            self.notifications = None;
+        }
+    }
+
+    pub fn stats(&self) -> &XhciStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = XhciStats::default();
+    }
+
+    /// Respond to a connect or disconnect on a root hub port, starting at 1
+    pub fn handle_port_event(&mut self, port: u8) -> Result<(), XhciError> {
+        if port == 0 || port as usize > self.ports.len() {
+            return Err(XhciError::PortError(port as usize));
+        }
+        let i = port as usize - 1;
+
+        let flags = self.ports[i].flags();
+        self.ports[i].clear_change_bits();
+
+        if flags.contains(port::PORT_OCC) && flags.contains(port::PORT_OCA) {
+            return self.handle_overcurrent(i);
+        }
+        // A device connected after the warm reset is reported by another Port Status Change Event
+        if self.escape_compliance_mode(i)? {
+            return Ok(());
+        }
+
+        if ! flags.contains(port::PORT_CCS) {
+            // Devices behind a hub on the port are gone as well
+            for slot in self.devices.slots_for_port(port) {
+                info!("   + XHCI Port {}: disconnected, disable slot {}", i, slot);
+                if let Err(err) = self.disable_slot(slot) {
+                    warn!("   + XHCI Port {}: failed to disable slot {}: {}", i, slot, err);
+                    self.devices.free_slot(slot);
+                }
+            }
+        } else if flags.contains(port::PORT_CSC) && self.devices.slot_for_port(port).is_none() {
+            // Such as a device that fell back to this USB 2.0 port after its USB 3.x port failed to train
+            info!("   + XHCI Port {}: connected, enumerate", i);
+            self.enumerate_port(i)?;
+        }
+
+        Ok(())
+    }
+
+    /// Warm reset a root hub port, starting at 0, if it is stuck in Compliance mode, returning whether it was
+    ///
+    /// The link failed to train if it is still in Compliance mode after the reset, or back in RxDetect although a
+    /// device was there.
+    fn escape_compliance_mode(&mut self, i: usize) -> Result<bool, XhciError> {
+        if ! self.ports[i].is_in_compliance_mode() {
+            return Ok(false);
+        }
+
+        info!("   + XHCI Port {}: compliance mode, warm reset", i);
+        let reset = self.ports[i].warm_reset();
+        let state = self.ports[i].link_state();
+        if reset.is_err() || state == PortLinkState::ComplianceMode || state == PortLinkState::RxDetect {
+            warn!("   + XHCI Port {}: link failed to train ({:?}), fall back to the companion port", i, state);
+            self.handle_usb3_train_failure(i)?;
+        }
+        Ok(true)
+    }
+
+    /// Turn off a USB 3.x root hub port, starting at 0, whose link failed to train, so that the device connects
+    /// to its USB 2.0 companion port instead
+    ///
+    /// The device is enumerated on the companion port by `handle_port_event` once its connection is reported.
+    pub fn handle_usb3_train_failure(&mut self, port3: usize) -> Result<(), XhciError> {
+        if port3 >= self.ports.len() {
+            return Err(XhciError::PortError(port3));
+        }
+        match self.port_protocol(port3) {
+            Some(ref protocol) if protocol.major >= 3 => (),
+            _ => return Err(XhciError::InvalidState("not a USB 3.x port")),
+        }
+        let port2 = match self.companion_port(port3) {
+            Some(port2) => port2,
+            None => {
+                warn!("   + XHCI Port {}: no companion port to fall back to", port3);
+                return Ok(());
+            },
+        };
+
+        // Without port power control, disabling the link keeps the port from training again
+        if self.cap.hccparams1().ppc {
+            debug!("    - Power off port {}", port3);
+            self.ports[port3].power_off();
+        } else {
+            debug!("    - Disable port {}", port3);
+            self.ports[port3].set_link_state(PortLinkState::Disabled)?;
+        }
+
+        info!("   + XHCI Port {}: turned off, device expected on companion port {}", port3, port2);
+        Ok(())
+    }
+
+    /// Register `f` to be called with the index of a root hub port, starting at 0, when it reports an over-current
+    /// condition
+    pub fn on_overcurrent<F: Fn(usize) + 'static>(&mut self, f: F) {
+        self.overcurrent = Some(Box::new(f));
+    }
+
+    /// Turn off the power of a root hub port in an over-current condition and disable the slots of the devices
+    /// behind it, so that enumeration is not retried on a shorted device
+    fn handle_overcurrent(&mut self, i: usize) -> Result<(), XhciError> {
+        error!("   + XHCI Port {}: over-current, power off", i);
+        self.ports[i].power_off();
+
+        for slot in self.devices.slots_for_port(i as u8 + 1) {
+            info!("   + XHCI Port {}: disable slot {}", i, slot);
+            if let Err(err) = self.disable_slot(slot) {
+                warn!("   + XHCI Port {}: failed to disable slot {}: {}", i, slot, err);
+                self.devices.free_slot(slot);
+            }
+        }
+
+        if let Some(ref f) = self.overcurrent {
+            f(i);
+        }
+        Ok(())
+    }
+
+    /// Enable a slot for a newly connected device and address it, returning the slot
+    fn attach_device(&mut self, route_string: u32, speed: PortSpeed, rh_port: u8, tt_slot: u8, tt_port: u8) -> Result<u8, XhciError> {
+        // Buffers are allocated first, so that a failed allocation does not leave a slot enabled
+        let ring = TransferRing::new()?;
+        let buffer = Dma::<[u8; CONTROL_BUFFER_SIZE]>::zeroed().or(Err(XhciError::DmaAlloc))?;
+
+        let mut input = InputContext::new(self.context_size)?;
+        {
+            input.add_slot();
+            input.add_endpoint(1);
+
+            input.set_slot_context(route_string, speed, 0, rh_port, tt_slot, tt_port, 0);
+            input.slot_mut().set_context_entries(1);
+
+            EndpointContextBuilder::new()
+                .max_packet_size(default_max_packet_size(speed))
+                .ep_type(EpType::Control)
+                .average_trb_length(8)
+                .dequeue(ring.register())
+                .build(input.endpoint_mut(0));
+        }
+
+        debug!("    - Enable slot");
+
+        let slot = self.command(|cmd, cycle| cmd.enable_slot(0, cycle))?;
+        self.devices.alloc_slot(slot)?;
+
+        debug!("    - Slot {}", slot);
+
+        self.devices.add_ring(slot, 1, ring);
+        self.devices.set_control_buffer(slot, buffer);
+        self.devices.set_input_context(slot, input);
+
+        let input_phys = self.devices.input_context(slot)?.physical() as u64;
+        let address = match self.assign_usb_address(slot, input_phys) {
+            Ok(address) => address,
+            Err(err) => {
+                // The output context of the slot is still zeroed, so it could not be found by its port later
+                if let Err(disable_err) = self.disable_slot(slot) {
+                    warn!("    - Failed to disable slot {}: {}", slot, disable_err);
+                    self.devices.free_slot(slot);
+                }
+                return Err(err);
+            },
+        };
+        debug!("    - Address {}", address);
+
+        Ok(slot)
+    }
+
+    /// Address and enumerate a device on a downstream port of a hub, starting at 1, returning its slot
+    pub fn attach_hub_device(&mut self, hub_slot: u8, port: u8, speed: PortSpeed) -> Result<u8, XhciError> {
+        let (route_string, rh_port, hub_speed, hub_tt) = {
+            let hub = self.devices.context(hub_slot)?.slot();
+            (hub.route_string(), hub.root_hub_port(), hub.speed(), (hub.tt_hub_slot(), hub.tt_port()))
+        };
+
+        let depth = self.hub_depth(hub_slot)?;
+        let mut path: Vec<u8> = (0..depth).map(|tier| ((route_string >> (tier * 4)) & 0xF) as u8).collect();
+        path.push(port);
+        let route_string = context::compute_route_string(&path)?;
+
+        // Full and low speed devices behind a high speed hub are reached through the hub's transaction
+        // translator, and devices behind a full speed hub use the translator that hub is behind
+        let (tt_slot, tt_port) = match speed {
+            PortSpeed::FullSpeed | PortSpeed::LowSpeed => match hub_speed {
+                Some(PortSpeed::HighSpeed) => (hub_slot, port),
+                Some(PortSpeed::FullSpeed) => hub_tt,
+                _ => (0, 0),
+            },
+            _ => (0, 0),
+        };
+
+        let slot = self.attach_device(route_string, speed, rh_port, tt_slot, tt_port)?;
+        if let Err(err) = self.enumerate_device(slot, rh_port as usize - 1) {
+            self.disable_slot(slot)?;
+            return Err(err);
+        }
+        Ok(slot)
+    }
+
+    /// Number of hubs between a slot and the root hub, from the tiers used in its route string
+    pub fn hub_depth(&self, slot: u8) -> Result<u8, XhciError> {
+        let route_string = self.devices.context(slot)?.slot().route_string();
+        Ok((0..5).take_while(|tier| (route_string >> (tier * 4)) & 0xF != 0).count() as u8)
+    }
+
+    /// Supported Protocol capability covering a root hub port, starting at 0
+    pub fn port_protocol(&self, port: usize) -> Option<ProtocolCapability> {
+        self.protocols.iter().find(|protocol| protocol.contains(port as u8 + 1)).cloned()
+    }
+
+    /// USB 2.0 companion of a USB 3.x root hub port or the other way around, both starting at 0
+    pub fn companion_port(&self, port: usize) -> Option<usize> {
+        capability::companion_port(&self.protocols, port as u8 + 1).map(|companion| companion as usize - 1)
+    }
+
+    /// Root hub port, starting at 1, through which the device of a slot is reached, from its output slot context
+    pub fn port_for_slot(&self, slot: u8) -> Result<u8, XhciError> {
+        if ! self.devices.is_used(slot) {
+            return Err(XhciError::InvalidState("slot not enabled"));
+        }
+        match self.devices.context(slot)?.slot().root_hub_port() {
+            0 => Err(XhciError::InvalidState("slot has no root hub port")),
+            port => Ok(port),
+        }
+    }
+
+    pub fn slot_speed(&self, slot: u8) -> Result<PortSpeed, XhciError> {
+        self.devices.context(slot)?.slot().speed().ok_or(XhciError::InvalidState("slot has no speed"))
+    }
+
+    /// Describe a configured hub to the controller, which must be done before devices behind it are addressed
+    pub fn configure_hub(&mut self, slot: u8, num_ports: u8, mtt: bool, ttt: u8) -> Result<(), XhciError> {
+        let mut input = InputContext::new(self.context_size)?;
+        input.add_slot();
+        input.copy_slot_context(self.devices.context(slot)?.slot());
+        input.slot_mut().set_hub(num_ports, mtt, ttt);
+        self.configure_endpoint(slot, &input)
+    }
+
+    /// Enumerate the device connected to a root hub port, returning its slot or `None` if the port is empty
+    ///
+    /// The slot of a device that fails to enumerate is disabled.
+    pub fn enumerate_port(&mut self, i: usize) -> Result<Option<u8>, XhciError> {
+        if i >= self.ports.len() {
+            return Err(XhciError::PortError(i));
+        }
+
+        let data = self.ports[i].read();
+        let state = self.ports[i].link_state();
+        let speed = self.ports[i].speed();
+        let flags = self.ports[i].flags();
+        info!("   + XHCI Port {}: {:X}, State {:?}, Speed {:?}, Flags {:?}", i, data, state, speed, flags);
+
+        if ! flags.contains(port::PORT_CCS) {
+            return Ok(None);
+        }
+
+        // USB 2.0 ports must be reset before they are enabled
+        if ! flags.contains(port::PORT_PED) {
+            debug!("    - Reset");
+            self.ports[i].reset()?;
+        }
+        // The connection is handled here, so it must not be reported again by a Port Status Change Event
+        self.ports[i].clear_change_bits();
+
+        let speed = self.ports[i].speed().ok_or(XhciError::PortError(i))?;
+        debug!("    - Speed: {}", speed);
+
+        let slot = self.attach_device(0, speed, i as u8 + 1, 0, 0)?;
+
+        debug!("    - Enumerate");
+
+        if let Err(err) = self.enumerate_device(slot, i) {
+            if let Err(disable_err) = self.disable_slot(slot) {
+                warn!("    - Failed to disable slot {}: {}", slot, disable_err);
+                self.devices.free_slot(slot);
+            }
+            return Err(err);
+        }
+        // Only what enumerate_device already read is logged, so that logging cannot fail a working device
+        if let Some(ddesc) = self.devices.descriptor(slot) {
+            debug!("      {:?}", ddesc);
+        }
+        if let Some(strings) = self.devices.strings(slot) {
+            debug!("        Manufacturer: {}", strings.manufacturer);
+            debug!("        Product: {}", strings.product);
+            debug!("        Serial: {}", strings.serial);
+        }
+
+        Ok(Some(slot))
+    }
+
+    /// Enumerate every root hub port, returning (port index, slot) pairs for the devices found.
+    /// A port that fails to enumerate is logged and skipped so that the remaining ports are still probed.
+    pub fn enumerate_all_ports(&mut self) -> Result<Vec<(usize, u8)>, XhciError> {
+        let mut devices = Vec::new();
+        for i in 0..self.ports.len() {
+            // Devices that are already enumerated keep their slot
+            if self.devices.slot_for_port(i as u8 + 1).is_some() {
+                continue;
+            }
+
+            match self.enumerate_port(i) {
+                Ok(Some(slot)) => devices.push((i, slot)),
+                Ok(None) => (),
+                // enumerate_port already disabled the slot of the device
+                Err(err) => error!("   + XHCI Port {}: failed to enumerate: {}", i, err),
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn probe(&mut self) -> Result<(), XhciError> {
+        for i in 0..self.ports.len() {
+            if let Err(err) = self.escape_compliance_mode(i) {
+                warn!("   + XHCI Port {}: failed to leave compliance mode: {}", i, err);
+            }
+        }
+
+        let devices = self.enumerate_all_ports()?;
+        info!("   + XHCI {} devices enumerated", devices.len());
+        Ok(())
+    }
+
+    pub fn irq(&mut self) -> bool {
+        if self.run.ints[0].is_pending() {
+            trace!("XHCI Interrupt");
+            if let Err(err) = self.poll() {
+                error!("XHCI Interrupt error: {}", err);
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// EP0 max packet size to use before the device descriptor has been read
+fn default_max_packet_size(speed: PortSpeed) -> u16 {
+    match speed {
+        PortSpeed::LowSpeed => 8,
+        PortSpeed::FullSpeed | PortSpeed::HighSpeed => 64,
+        PortSpeed::SuperSpeed | PortSpeed::SuperSpeedPlus => 512,
+    }
+}
+
+/// Bytes a bus of `speed` can carry in one frame, or one microframe above full speed, before protocol overhead
+fn bytes_per_interval(speed: PortSpeed) -> u32 {
+    match speed {
+        PortSpeed::LowSpeed => 187,
+        PortSpeed::FullSpeed => 1500,
+        PortSpeed::HighSpeed => 7500,
+        // 5 Gb/s with 8b/10b encoding and 10 Gb/s with 128b/132b encoding
+        PortSpeed::SuperSpeed => 62500,
+        PortSpeed::SuperSpeedPlus => 151515,
+    }
+}
+
+/// Percentage of the bandwidth of a bus of `speed`, rounded up, used by `esit_payload` bytes per (micro)frame
+fn bandwidth_percentage(speed: PortSpeed, esit_payload: u32) -> u32 {
+    let bytes = bytes_per_interval(speed) as u64;
+    ((esit_payload as u64 * 100 + bytes - 1) / bytes) as u32
+}
+
+/// Check that the `size` bytes of registers at `addr` lie inside the `bar_len` bytes mapped at `bar_base`
+fn validate_region(addr: usize, size: usize, bar_base: usize, bar_len: usize) -> Result<(), XhciError> {
+    let bar_end = bar_base.checked_add(bar_len).ok_or(XhciError::InvalidState("BAR mapping wraps around"))?;
+    match addr.checked_add(size) {
+        Some(end) if addr >= bar_base && end <= bar_end => Ok(()),
+        _ => Err(XhciError::InvalidState("registers outside of the BAR mapping")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn iso_bandwidth_percentage() {
+        assert_eq!(bandwidth_percentage(PortSpeed::FullSpeed, 0), 0);
+        assert_eq!(bandwidth_percentage(PortSpeed::FullSpeed, 1023), 69);
+        assert_eq!(bandwidth_percentage(PortSpeed::HighSpeed, 3 * 1024), 41);
+        assert_eq!(bandwidth_percentage(PortSpeed::SuperSpeed, 62500), 100);
+    }
+
+    #[test]
+    fn region_inside_bar() {
+        assert!(validate_region(0x1000, 0x20, 0x1000, 0x10000).is_ok());
+        assert!(validate_region(0x10FE0, 0x20, 0x1000, 0x10000).is_ok());
+        assert!(validate_region(0x10FE0, 0x21, 0x1000, 0x10000).is_err());
+        assert!(validate_region(0xFFF, 0x20, 0x1000, 0x10000).is_err());
+        assert!(validate_region(usize::max_value() - 0x10, 0x20, 0x1000, 0x10000).is_err());
     }
 }
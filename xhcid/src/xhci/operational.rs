@@ -1,4 +1,4 @@
-use syscall::io::Mmio;
+use syscall::io::{Io, Mmio};
 
 #[repr(packed)]
 pub struct OperationalRegs {
@@ -12,3 +12,117 @@ pub struct OperationalRegs {
     pub dcbaap: Mmio<u64>,
     pub config: Mmio<u32>,
 }
+
+bitflags! {
+    /// Device Notification Transaction Packet types reported as Device Notification Events, one bit per type in DNCTRL
+    pub flags DeviceNotificationTypes: u16 {
+        const DN_FUNCTION_WAKE = 1 << 1,
+        const DN_LATENCY_TOLERANCE = 1 << 2,
+        const DN_BUS_INTERVAL_ADJUSTMENT = 1 << 3,
+        const DN_HOST_ROLE_REQUEST = 1 << 4,
+        const DN_SUBLINK_SPEED = 1 << 5,
+    }
+}
+
+/// USBCMD bits
+const USB_CMD_RS: u32 = 1 << 0;
+const USB_CMD_HCRST: u32 = 1 << 1;
+const USB_CMD_INTE: u32 = 1 << 2;
+const USB_CMD_HSEE: u32 = 1 << 3;
+const USB_CMD_LHCRST: u32 = 1 << 7;
+
+/// CRCR bits
+const CRCR_CA: u64 = 1 << 2;
+const CRCR_CRR: u64 = 1 << 3;
+
+/// USBSTS bits
+const USB_STS_HCH: u32 = 1 << 0;
+const USB_STS_HSE: u32 = 1 << 2;
+const USB_STS_EINT: u32 = 1 << 3;
+const USB_STS_PCD: u32 = 1 << 4;
+const USB_STS_CNR: u32 = 1 << 11;
+const USB_STS_HCE: u32 = 1 << 12;
+
+impl OperationalRegs {
+    /// Start or stop the controller, `halted` reports when it has stopped
+    pub fn set_run_stop(&mut self, run: bool) {
+        self.usb_cmd.writef(USB_CMD_RS, run);
+    }
+
+    /// Start a controller reset, which is complete once `hcrst_pending` is clear
+    pub fn trigger_hcrst(&mut self) {
+        self.usb_cmd.writef(USB_CMD_HCRST, true);
+    }
+
+    pub fn hcrst_pending(&self) -> bool {
+        self.usb_cmd.readf(USB_CMD_HCRST)
+    }
+
+    /// Allow interrupters to generate interrupts
+    pub fn set_interrupter_enable(&mut self, enable: bool) {
+        self.usb_cmd.writef(USB_CMD_INTE, enable);
+    }
+
+    /// Allow Host System Errors to be reported to the system
+    pub fn set_host_system_error_enable(&mut self, enable: bool) {
+        self.usb_cmd.writef(USB_CMD_HSEE, enable);
+    }
+
+    /// Reset the controller without affecting the state of the ports, if HCCPARAMS1.LHRC is set
+    pub fn set_light_reset(&mut self) {
+        self.usb_cmd.writef(USB_CMD_LHCRST, true);
+    }
+
+    /// Stop the command ring after the current command, the controller reports a Command Ring Stopped event
+    pub fn abort_command_ring(&mut self) {
+        // The command ring pointer is ignored while the ring is running, so only CA needs to be set
+        self.crcr.write(CRCR_CA);
+    }
+
+    /// Command Ring Running, set while the controller is processing commands
+    pub fn command_ring_running(&self) -> bool {
+        self.crcr.readf(CRCR_CRR)
+    }
+
+    /// Enable Device Notification Events for the notification types set in `types`, bit N being type N
+    pub fn set_device_notification(&mut self, types: u16) {
+        let dn_ctrl = self.dn_ctrl.read() & !0xFFFF;
+        self.dn_ctrl.write(dn_ctrl | types as u32);
+    }
+
+    /// HCHalted, set once the controller has stopped after run/stop is cleared
+    pub fn halted(&self) -> bool {
+        self.usb_sts.readf(USB_STS_HCH)
+    }
+
+    /// Host System Error, set on a serious error such as a PCI parity error
+    pub fn host_system_error(&self) -> bool {
+        self.usb_sts.readf(USB_STS_HSE)
+    }
+
+    /// Event Interrupt, set when an interrupter's Interrupt Pending bit is set
+    pub fn event_interrupt(&self) -> bool {
+        self.usb_sts.readf(USB_STS_EINT)
+    }
+
+    /// Port Change Detect, set when any port has a change bit set
+    pub fn port_change_detect(&self) -> bool {
+        self.usb_sts.readf(USB_STS_PCD)
+    }
+
+    /// Controller Not Ready, no registers other than the capability registers may be written while it is set
+    pub fn is_controller_not_ready(&self) -> bool {
+        self.usb_sts.readf(USB_STS_CNR)
+    }
+
+    /// Host Controller Error, set on an internal error that requires a reset
+    pub fn host_error(&self) -> bool {
+        self.usb_sts.readf(USB_STS_HCE)
+    }
+
+    /// Clear Event Interrupt, which is cleared by writing one
+    pub fn clear_event_interrupt(&mut self) {
+        // Writing zero to the other bits leaves them as they are
+        self.usb_sts.write(USB_STS_EINT);
+    }
+}
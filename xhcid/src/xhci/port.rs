@@ -1,31 +1,189 @@
+use std::convert::TryFrom;
+use std::{fmt, thread};
+use std::time::Duration;
 use syscall::io::{Io, Mmio};
 
+use super::error::XhciError;
+use super::timeout::Timeout;
+use super::TIMEOUT_MS;
+
 bitflags! {
+    /// Single bit fields of PORTSC
     pub flags PortFlags: u32 {
+        /// Current Connect Status
         const PORT_CCS = 1 << 0,
+        /// Port Enabled/Disabled, cleared by writing one
         const PORT_PED = 1 << 1,
+        /// Over-current Active
         const PORT_OCA = 1 << 3,
+        /// Port Reset
         const PORT_PR =  1 << 4,
+        /// Port Power
         const PORT_PP =  1 << 9,
+        /// Port Indicator Control, amber and green
         const PORT_PIC_AMB = 1 << 14,
         const PORT_PIC_GRN = 1 << 15,
+        /// Port Link State Write Strobe
         const PORT_LWS = 1 << 16,
+        /// Connect Status Change
         const PORT_CSC = 1 << 17,
+        /// Port Enabled/Disabled Change
         const PORT_PEC = 1 << 18,
+        /// Warm Port Reset Change
         const PORT_WRC = 1 << 19,
+        /// Over-current Change
         const PORT_OCC = 1 << 20,
+        /// Port Reset Change
         const PORT_PRC = 1 << 21,
+        /// Port Link State Change
         const PORT_PLC = 1 << 22,
+        /// Port Config Error Change
         const PORT_CEC = 1 << 23,
+        /// Cold Attach Status
         const PORT_CAS = 1 << 24,
+        /// Wake on Connect, Disconnect and Over-current Enable
         const PORT_WCE = 1 << 25,
         const PORT_WDE = 1 << 26,
         const PORT_WOE = 1 << 27,
+        /// Device Removable
         const PORT_DR =  1 << 30,
+        /// Warm Port Reset
         const PORT_WPR = 1 << 31,
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortLinkState {
+    U0,
+    U1,
+    U2,
+    U3,
+    Disabled,
+    RxDetect,
+    Inactive,
+    Polling,
+    Recovery,
+    HotReset,
+    ComplianceMode,
+    TestMode,
+    Resume,
+    Reserved(u8),
+}
+
+impl From<u8> for PortLinkState {
+    fn from(state: u8) -> PortLinkState {
+        match state {
+            0 => PortLinkState::U0,
+            1 => PortLinkState::U1,
+            2 => PortLinkState::U2,
+            3 => PortLinkState::U3,
+            4 => PortLinkState::Disabled,
+            5 => PortLinkState::RxDetect,
+            6 => PortLinkState::Inactive,
+            7 => PortLinkState::Polling,
+            8 => PortLinkState::Recovery,
+            9 => PortLinkState::HotReset,
+            10 => PortLinkState::ComplianceMode,
+            11 => PortLinkState::TestMode,
+            15 => PortLinkState::Resume,
+            reserved => PortLinkState::Reserved(reserved)
+        }
+    }
+}
+
+impl Into<u8> for PortLinkState {
+    fn into(self) -> u8 {
+        match self {
+            PortLinkState::U0 => 0,
+            PortLinkState::U1 => 1,
+            PortLinkState::U2 => 2,
+            PortLinkState::U3 => 3,
+            PortLinkState::Disabled => 4,
+            PortLinkState::RxDetect => 5,
+            PortLinkState::Inactive => 6,
+            PortLinkState::Polling => 7,
+            PortLinkState::Recovery => 8,
+            PortLinkState::HotReset => 9,
+            PortLinkState::ComplianceMode => 10,
+            PortLinkState::TestMode => 11,
+            PortLinkState::Resume => 15,
+            PortLinkState::Reserved(reserved) => reserved
+        }
+    }
+}
+
+/// Port speed IDs of the default Protocol Speed ID mapping
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum PortSpeed {
+    FullSpeed = 1,
+    LowSpeed = 2,
+    HighSpeed = 3,
+    SuperSpeed = 4,
+    SuperSpeedPlus = 5,
+}
+
+impl TryFrom<u8> for PortSpeed {
+    type Error = u8;
+
+    fn try_from(speed: u8) -> Result<PortSpeed, u8> {
+        match speed {
+            1 => Ok(PortSpeed::FullSpeed),
+            2 => Ok(PortSpeed::LowSpeed),
+            3 => Ok(PortSpeed::HighSpeed),
+            4 => Ok(PortSpeed::SuperSpeed),
+            5 => Ok(PortSpeed::SuperSpeedPlus),
+            other => Err(other)
+        }
+    }
+}
+
+impl fmt::Display for PortSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            PortSpeed::FullSpeed => "full speed",
+            PortSpeed::LowSpeed => "low speed",
+            PortSpeed::HighSpeed => "high speed",
+            PortSpeed::SuperSpeed => "SuperSpeed",
+            PortSpeed::SuperSpeedPlus => "SuperSpeedPlus",
+        })
+    }
+}
+
+/// Time for port power to become stable after it has been turned on, in milliseconds
+const POWER_ON_DELAY_MS: u64 = 20;
+
+/// Time resume signalling is driven on a USB 2.0 port before its link is moved back to U0, in milliseconds
+const RESUME_SIGNAL_MS: u64 = 20;
+
+/// Bits of PORTSC that are cleared by writing one, and so must be masked when writing other bits
+const PORTSC_RW1C_MASK: u32 = 0x00FE0002;
+
+/// Remote Wake Enable of the PORTPMSC of a USB 2.0 port
+const PORTPMSC_RWE: u32 = 1 << 3;
+
+/// L1 Status of the PORTPMSC of a USB 2.0 port, the result of the last attempt to enter L1
+const PORTPMSC_L1S_MASK: u32 = 0b111;
+const L1S_INVALID: u32 = 0;
+const L1S_SUCCESS: u32 = 1;
+const L1S_NOT_YET: u32 = 2;
+const L1S_NOT_SUPPORTED: u32 = 3;
+
+/// Best Effort Service Latency and L1 Device Slot fields of the PORTPMSC of a USB 2.0 port
+const PORTPMSC_BESL_MASK: u32 = 0xF << 4;
+const PORTPMSC_BESL_SHIFT: u32 = 4;
+const PORTPMSC_L1_SLOT_MASK: u32 = 0xFF << 8;
+const PORTPMSC_L1_SLOT_SHIFT: u32 = 8;
+
+/// Port Link State field of PORTSC
+const PORTSC_PLS_MASK: u32 = 0b1111 << 5;
+const PORTSC_PLS_SHIFT: u32 = 5;
+
+/// Change bits of PORTSC, which are set by the controller and cleared by writing one
+fn change_flags() -> PortFlags {
+    PORT_CSC | PORT_PEC | PORT_WRC | PORT_OCC | PORT_PRC | PORT_PLC | PORT_CEC
+}
+
 #[repr(packed)]
 pub struct Port {
     pub portsc : Mmio<u32>,
@@ -39,15 +197,233 @@ impl Port {
         self.portsc.read()
     }
 
-    pub fn state(&self) -> u32 {
-        (self.read() & (0b1111 << 5)) >> 5
+    pub fn link_state(&self) -> PortLinkState {
+        PortLinkState::from(((self.read() & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT) as u8)
     }
 
-    pub fn speed(&self) -> u32 {
-        (self.read() & (0b1111 << 10)) >> 10
+    /// Speed of the connected device, or `None` if there is none or it uses a non-default speed ID
+    pub fn speed(&self) -> Option<PortSpeed> {
+        PortSpeed::try_from(((self.read() >> 10) & 0b1111) as u8).ok()
     }
 
     pub fn flags(&self) -> PortFlags {
         PortFlags::from_bits_truncate(self.read())
     }
+
+    /// Write PORTSC without clearing any change bits or disabling the port, except those given in `set_bits`
+    pub fn write_portsc_preserve_rw1c(&mut self, set_bits: u32, clear_bits: u32) {
+        let data = self.read() & !PORTSC_RW1C_MASK;
+        self.portsc.write((data | set_bits) & !clear_bits);
+    }
+
+    fn set_flags(&mut self, flags: PortFlags) {
+        self.write_portsc_preserve_rw1c(flags.bits(), 0);
+    }
+
+    fn wait_for_flags(&self, flags: PortFlags) -> Result<(), XhciError> {
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        while ! self.flags().contains(flags) {
+            timeout.check()?;
+        }
+        Ok(())
+    }
+
+    /// Set the U1 and U2 inactivity timeouts of a USB 3.x port, after which the link enters U1 or U2.
+    /// 0 disables the state, U1 is in microseconds and U2 in units of 256 microseconds.
+    pub fn set_u1_u2_timeouts(&mut self, u1_timeout: u8, u2_timeout: u8) {
+        let portpmsc = self.portpmsc.read() & !0xFFFF;
+        self.portpmsc.write(portpmsc | ((u2_timeout as u32) << 8) | u1_timeout as u32);
+    }
+
+    /// Write the Port Link State field, which the controller only accepts together with Link Write Strobe
+    fn write_link_state(&mut self, state: PortLinkState) {
+        let state: u8 = state.into();
+        let data = self.read() & !(PORTSC_RW1C_MASK | PORTSC_PLS_MASK);
+        self.portsc.write(data | PORT_LWS.bits() | ((state as u32) << PORTSC_PLS_SHIFT));
+    }
+
+    /// Move the link to `state`, one of the states software may write to PLS, and wait for the transition
+    pub fn set_link_state(&mut self, state: PortLinkState) -> Result<(), XhciError> {
+        match state {
+            PortLinkState::U0 | PortLinkState::U2 | PortLinkState::RxDetect | PortLinkState::Resume => (),
+            PortLinkState::U3 => match self.speed() {
+                Some(PortSpeed::FullSpeed) | Some(PortSpeed::LowSpeed) => {
+                    return Err(XhciError::InvalidState("U3 is not supported on full or low speed ports"));
+                },
+                _ => (),
+            },
+            PortLinkState::Disabled => if ! self.flags().contains(PORT_PP) {
+                return Err(XhciError::InvalidState("port is powered off"));
+            },
+            _ => return Err(XhciError::InvalidState("link state cannot be written")),
+        }
+
+        self.write_link_state(state);
+
+        // Not every transition sets PLC, such as entering U3, so the link state is checked as well
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        while ! self.flags().contains(PORT_PLC) && self.link_state() != state {
+            timeout.check()?;
+        }
+        if self.flags().contains(PORT_PLC) {
+            self.set_flags(PORT_PLC);
+        }
+        Ok(())
+    }
+
+    /// Put the link of a USB 2.0 port into L1 (Sleep), which is encoded as U2, with an LPM transaction sent to
+    /// the device of `slot` and `besl` as its Best Effort Service Latency
+    pub fn enable_lpm(&mut self, slot: u8, besl: u8) -> Result<(), XhciError> {
+        match self.speed() {
+            Some(PortSpeed::FullSpeed) | Some(PortSpeed::HighSpeed) => (),
+            _ => return Err(XhciError::InvalidState("L1 requires a full or high speed device")),
+        }
+        if besl > 0xF {
+            return Err(XhciError::InvalidState("BESL larger than 4 bits"));
+        }
+
+        // The L1 Device Slot must be written before PLS (xHCI 4.23.5.1.1)
+        let portpmsc = self.portpmsc.read() & !(PORTPMSC_L1S_MASK | PORTPMSC_BESL_MASK | PORTPMSC_L1_SLOT_MASK);
+        self.portpmsc.write(portpmsc | ((slot as u32) << PORTPMSC_L1_SLOT_SHIFT) | ((besl as u32) << PORTPMSC_BESL_SHIFT));
+        self.write_link_state(PortLinkState::U2);
+
+        // L1S may still hold the result of an earlier attempt until the link gets to L1
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        loop {
+            match self.portpmsc.read() & PORTPMSC_L1S_MASK {
+                L1S_SUCCESS if self.link_state() == PortLinkState::U2 => return Ok(()),
+                L1S_INVALID | L1S_SUCCESS => timeout.check()?,
+                L1S_NOT_YET => return Err(XhciError::InvalidState("device not ready to enter L1")),
+                L1S_NOT_SUPPORTED => return Err(XhciError::InvalidState("device does not support L1")),
+                _ => return Err(XhciError::InvalidState("LPM transaction failed")),
+            }
+        }
+    }
+
+    /// Return the link of a USB 2.0 port from L1 to L0, which is encoded as U0
+    pub fn exit_lpm(&mut self) -> Result<(), XhciError> {
+        match self.speed() {
+            Some(PortSpeed::FullSpeed) | Some(PortSpeed::HighSpeed) => (),
+            _ => return Err(XhciError::InvalidState("L1 requires a full or high speed device")),
+        }
+        self.write_link_state(PortLinkState::U0);
+        Ok(())
+    }
+
+    /// Start resuming the link of a suspended USB 3.x port, which reports a link state change once it reaches U0
+    pub fn exit_u3(&mut self) -> Result<(), XhciError> {
+        match self.speed() {
+            Some(PortSpeed::SuperSpeed) | Some(PortSpeed::SuperSpeedPlus) => (),
+            _ => return Err(XhciError::InvalidState("U3 exit requires a USB 3.x device")),
+        }
+        if self.link_state() != PortLinkState::U3 {
+            return Err(XhciError::InvalidState("port link is not in U3"));
+        }
+        self.write_link_state(PortLinkState::U0);
+        Ok(())
+    }
+
+    /// Put the link of an enabled port into U3 (Suspend) and wait until it gets there
+    pub fn suspend(&mut self) -> Result<(), XhciError> {
+        self.write_link_state(PortLinkState::U3);
+        // PLC is not set when software moves a link into U3, so the link state is polled instead
+        let timeout = Timeout::from_millis(TIMEOUT_MS);
+        while self.link_state() != PortLinkState::U3 {
+            timeout.check()?;
+        }
+        Ok(())
+    }
+
+    /// Bring the link of a suspended port back to U0 and wait for the link state change
+    pub fn resume(&mut self) -> Result<(), XhciError> {
+        if self.link_state() != PortLinkState::U3 {
+            return Err(XhciError::InvalidState("port link is not in U3"));
+        }
+        match self.speed() {
+            Some(PortSpeed::SuperSpeed) | Some(PortSpeed::SuperSpeedPlus) => self.write_link_state(PortLinkState::U0),
+            _ => {
+                self.write_link_state(PortLinkState::Resume);
+                thread::sleep(Duration::from_millis(RESUME_SIGNAL_MS));
+                self.write_link_state(PortLinkState::U0);
+            },
+        }
+        self.wait_for_flags(PORT_PLC)?;
+        self.set_flags(PORT_PLC);
+        Ok(())
+    }
+
+    /// Let the port wake the system when its device signals remote wakeup, or on connect, disconnect and over-current
+    pub fn arm_wakeup(&mut self) {
+        self.set_flags(PORT_WCE | PORT_WDE | PORT_WOE);
+        // USB 3.x ports take remote wakeup out of U3 without being armed
+        match self.speed() {
+            Some(PortSpeed::SuperSpeed) | Some(PortSpeed::SuperSpeedPlus) => (),
+            _ => self.portpmsc.writef(PORTPMSC_RWE, true),
+        }
+    }
+
+    /// Acknowledge all change bits that are set, so that the next change generates a Port Status Change Event
+    pub fn clear_change_bits(&mut self) {
+        let changes = self.flags() & change_flags();
+        if ! changes.is_empty() {
+            self.set_flags(changes);
+        }
+    }
+
+    /// Turn the port's power on or off, which only has an effect if HCCPARAMS1.PPC is set
+    pub fn set_power(&mut self, on: bool) {
+        if on {
+            self.write_portsc_preserve_rw1c(PORT_PP.bits(), 0);
+        } else {
+            self.write_portsc_preserve_rw1c(0, PORT_PP.bits());
+        }
+    }
+
+    /// Turn the port's power on and wait for it to become stable
+    pub fn power_on(&mut self) -> Result<(), XhciError> {
+        self.set_power(true);
+        thread::sleep(Duration::from_millis(POWER_ON_DELAY_MS));
+        if self.flags().contains(PORT_PP) {
+            Ok(())
+        } else {
+            // Power stays off while the port is in an over-current condition
+            Err(XhciError::InvalidState("port power did not turn on"))
+        }
+    }
+
+    pub fn power_off(&mut self) {
+        self.set_power(false);
+    }
+
+    /// Reset the port, which enables USB 2.0 ports after a device has connected.
+    pub fn reset(&mut self) -> Result<(), XhciError> {
+        self.set_flags(PORT_PR);
+        self.wait_for_flags(PORT_PRC)?;
+        self.set_flags(PORT_PRC);
+        Ok(())
+    }
+
+    /// A USB 3.x port enters Compliance mode if training fails, and stays there until it is warm reset
+    pub fn is_in_compliance_mode(&self) -> bool {
+        self.link_state() == PortLinkState::ComplianceMode
+    }
+
+    /// Warm reset a USB 3.x port, which is required to leave the Compliance or Inactive link states.
+    pub fn warm_reset(&mut self) -> Result<(), XhciError> {
+        self.set_flags(PORT_WPR);
+        self.wait_for_flags(PORT_WRC)?;
+        self.set_flags(PORT_WRC | PORT_PRC);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rw1c_mask() {
+        // PED is also cleared by writing one, which disables the port
+        assert_eq!((change_flags() | PORT_PED).bits(), PORTSC_RW1C_MASK);
+    }
 }
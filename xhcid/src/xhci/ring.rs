@@ -1,25 +1,26 @@
-use syscall::error::Result;
 use syscall::io::Dma;
 
+use super::error::XhciError;
 use super::trb::Trb;
 
 pub struct Ring {
     pub link: bool,
-    pub trbs: Dma<[Trb; 16]>,
+    pub trbs: Dma<[Trb; 256]>,
     pub i: usize,
     pub cycle: bool,
 }
 
 impl Ring {
-    pub fn new(link: bool) -> Result<Ring> {
+    pub fn new(link: bool) -> Result<Ring, XhciError> {
         Ok(Ring {
             link: link,
-            trbs: Dma::zeroed()?,
+            trbs: Dma::zeroed().or(Err(XhciError::DmaAlloc))?,
             i: 0,
             cycle: link,
         })
     }
 
+    /// Physical address of the enqueue pointer, with the cycle state in bit 0
     pub fn register(&self) -> u64 {
         let base = self.trbs.physical() as *const Trb;
         let addr = unsafe { base.offset(self.i as isize) };
@@ -27,23 +28,20 @@ impl Ring {
     }
 
     pub fn next(&mut self) -> (&mut Trb, bool) {
-        let mut i;
-        loop {
-            i = self.i;
-            self.i += 1;
-            if self.i >= self.trbs.len() {
-                self.i = 0;
-
-                if self.link {
-                    let address = self.trbs.physical();
-                    self.trbs[i].link(address, true, self.cycle);
-                    self.cycle = !self.cycle;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
+        if self.link && self.i == self.trbs.len() - 1 {
+            // Hand over the Link TRB, which sends the controller back to the start and toggles its cycle state
+            let i = self.i;
+            let address = self.trbs.physical();
+            self.trbs[i].link(address, true, self.cycle);
+
+            self.i = 0;
+            self.cycle = !self.cycle;
+        }
+
+        let i = self.i;
+        self.i += 1;
+        if self.i >= self.trbs.len() {
+            self.i = 0;
         }
 
         (&mut self.trbs[i], self.cycle)
@@ -1,7 +1,8 @@
-use syscall::io::Mmio;
+use std::cmp;
+use syscall::io::{Io, Mmio};
 
 #[repr(packed)]
-pub struct Interrupter {
+pub struct InterrupterRegs {
     pub iman: Mmio<u32>,
     pub imod: Mmio<u32>,
     pub erstsz: Mmio<u32>,
@@ -10,9 +11,59 @@ pub struct Interrupter {
     pub erdp: Mmio<u64>,
 }
 
+/// IMAN bits
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+
+impl InterrupterRegs {
+    pub fn enable_interrupts(&mut self) {
+        // Writing back a set Interrupt Pending bit would clear it
+        let iman = self.iman.read() & !IMAN_IP;
+        self.iman.write(iman | IMAN_IE);
+    }
+
+    pub fn disable_interrupts(&mut self) {
+        let iman = self.iman.read() & !(IMAN_IP | IMAN_IE);
+        self.iman.write(iman);
+    }
+
+    /// Interrupt Pending, set when an interrupt is generated until it is cleared by software
+    pub fn is_pending(&self) -> bool {
+        self.iman.readf(IMAN_IP)
+    }
+
+    pub fn clear_pending(&mut self) {
+        // Interrupt Pending is cleared by writing one
+        let iman = self.iman.read() & IMAN_IE;
+        self.iman.write(iman | IMAN_IP);
+    }
+}
+
 #[repr(packed)]
 pub struct RuntimeRegs {
     pub mfindex: Mmio<u32>,
     _rsvd: [Mmio<u32>; 7],
-    pub ints: [Interrupter; 1024],
+    pub ints: [InterrupterRegs; 1024],
+}
+
+impl RuntimeRegs {
+    /// Microframe Index, incremented every 125 us and wrapping after 0x3FFF
+    pub fn mfindex(&self) -> u16 {
+        (self.mfindex.read() & 0x3FFF) as u16
+    }
+
+    /// The interrupters the controller implements, `count` being HCSPARAMS1.MaxIntrs
+    pub fn interrupters(&self, count: u16) -> &[InterrupterRegs] {
+        &self.ints[..cmp::min(count as usize, self.ints.len())]
+    }
+
+    pub fn interrupters_mut(&mut self, count: u16) -> &mut [InterrupterRegs] {
+        let count = cmp::min(count as usize, self.ints.len());
+        &mut self.ints[..count]
+    }
+
+    /// Set the minimum interval between interrupts of interrupter 0, in 250 ns units, and restart its counter
+    pub fn set_moderation(&mut self, interval_250ns: u16) {
+        self.ints[0].imod.write(interval_250ns as u32);
+    }
 }
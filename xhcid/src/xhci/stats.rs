@@ -0,0 +1,49 @@
+use std::convert::TryFrom;
+
+use super::event::XhciEvent;
+use super::trb::CompletionCode;
+
+/// Counts of the events handled by the driver, and of the error completion codes they carried
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XhciStats {
+    pub command_completions: u64,
+    pub transfer_events: u64,
+    pub port_status_changes: u64,
+    pub babble_errors: u64,
+    pub transaction_errors: u64,
+    pub stall_errors: u64,
+    pub ring_overruns: u64,
+    pub event_ring_full: u64,
+}
+
+impl XhciStats {
+    pub fn record(&mut self, event: &XhciEvent) {
+        let code = match *event {
+            XhciEvent::Transfer { code, .. } | XhciEvent::EventData { code, .. } => {
+                self.transfer_events += 1;
+                code
+            },
+            XhciEvent::CommandCompletion { code, .. } => {
+                self.command_completions += 1;
+                code
+            },
+            XhciEvent::PortStatusChange { .. } => {
+                self.port_status_changes += 1;
+                return;
+            },
+            XhciEvent::DeviceNotification { code, .. } => code,
+            XhciEvent::MfindexWrap { code } => code,
+            // Event Ring Full is reported by a Host Controller Event
+            XhciEvent::Other { status, .. } => (status >> 24) as u8,
+        };
+
+        match CompletionCode::try_from(code) {
+            Ok(CompletionCode::BabbleDetected) => self.babble_errors += 1,
+            Ok(CompletionCode::UsbTransaction) => self.transaction_errors += 1,
+            Ok(CompletionCode::Stall) => self.stall_errors += 1,
+            Ok(CompletionCode::RingOverrun) => self.ring_overruns += 1,
+            Ok(CompletionCode::EventRingFull) => self.event_ring_full += 1,
+            _ => (),
+        }
+    }
+}
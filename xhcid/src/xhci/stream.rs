@@ -0,0 +1,95 @@
+use std::cmp;
+use syscall::io::{Dma, Io, Mmio};
+
+use super::error::XhciError;
+
+/// Most streams of an endpoint, stream ID 0 is reserved so a 256 entry array holds 255
+pub const MAX_STREAMS: u16 = 255;
+
+/// Stream Context Type, where the TR dequeue pointer of a stream context points
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum StreamContextType {
+    /// A transfer ring of a stream of a secondary stream array
+    Secondary = 0,
+    /// A transfer ring of a stream of the primary stream array
+    Primary = 1,
+}
+
+#[repr(packed)]
+pub struct StreamContext {
+    pub trl: Mmio<u32>,
+    pub trh: Mmio<u32>,
+    /// Stopped EDTLA
+    pub edtla: Mmio<u32>,
+    _rsvd: Mmio<u32>,
+}
+
+impl StreamContext {
+    /// Point the stream at a transfer ring, `dequeue` being the ring's register value with the cycle state in bit 0
+    pub fn set_dequeue(&mut self, dequeue: u64, kind: StreamContextType) {
+        self.trl.write((dequeue as u32 & !0xE) | ((kind as u32) << 1));
+        self.trh.write((dequeue >> 32) as u32);
+    }
+}
+
+/// Number of entries of a primary stream array for stream IDs 1 to `streams`, a power of two
+///
+/// MaxPStreams 0 means the endpoint has no streams, so the array has at least 4 entries.
+fn array_size(streams: u16) -> usize {
+    cmp::max((streams as usize + 1).next_power_of_two(), 4)
+}
+
+/// MaxPStreams field of an endpoint context for a primary stream array of `size` entries
+fn max_primary_streams(size: usize) -> u8 {
+    size.trailing_zeros() as u8 - 1
+}
+
+/// Primary stream context array of an endpoint, indexed by stream ID
+pub struct StreamContextArray {
+    pub contexts: Dma<[StreamContext; MAX_STREAMS as usize + 1]>,
+    /// Number of entries the controller is told about, a power of two
+    size: usize,
+}
+
+impl StreamContextArray {
+    /// Allocate an array large enough for stream IDs 1 to `streams`
+    pub fn new(streams: u16) -> Result<StreamContextArray, XhciError> {
+        if streams == 0 || streams > MAX_STREAMS {
+            return Err(XhciError::InvalidState("invalid number of streams"));
+        }
+
+        Ok(StreamContextArray {
+            contexts: Dma::zeroed().or(Err(XhciError::DmaAlloc))?,
+            size: array_size(streams),
+        })
+    }
+
+    pub fn physical(&self) -> usize {
+        self.contexts.physical()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// MaxPStreams field of the endpoint context, the array holds 2^(MaxPStreams + 1) entries
+    pub fn max_primary_streams(&self) -> u8 {
+        max_primary_streams(self.size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn primary_array_sizes() {
+        assert_eq!(array_size(1), 4);
+        assert_eq!(max_primary_streams(array_size(1)), 1);
+        assert_eq!(array_size(3), 4);
+        assert_eq!(max_primary_streams(array_size(3)), 1);
+        assert_eq!(array_size(255), 256);
+        assert_eq!(max_primary_streams(array_size(255)), 7);
+    }
+}
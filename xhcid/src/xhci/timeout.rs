@@ -0,0 +1,30 @@
+use std::time::{Duration, Instant};
+
+use super::error::XhciError;
+
+pub struct Timeout {
+    instant: Instant,
+    duration: Duration,
+}
+
+impl Timeout {
+    pub fn new(duration: Duration) -> Timeout {
+        Timeout {
+            instant: Instant::now(),
+            duration: duration,
+        }
+    }
+
+    pub fn from_millis(millis: u64) -> Timeout {
+        Timeout::new(Duration::from_millis(millis))
+    }
+
+    /// Return an error once the timeout has expired, to be called on each iteration of a polling loop.
+    pub fn check(&self) -> Result<(), XhciError> {
+        if self.instant.elapsed() < self.duration {
+            Ok(())
+        } else {
+            Err(XhciError::ControllerTimeout)
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::mem;
+use syscall::io::Dma;
+
+use super::error::XhciError;
+use super::ring::Ring;
+use super::trb::{Trb, TrbType};
+
+/// Largest transfer a single TRB can describe
+pub const MAX_TRB_TRANSFER: usize = 0x10000;
+
+/// Transfer Burst Count and Last Burst Packet Count of an isochronous TD, as described in xHCI 4.11.2.3
+fn burst_counts(len: usize, max_packet_size: u16, max_burst_size: u8) -> (u8, u8) {
+    let packets = (len + max_packet_size as usize - 1) / max_packet_size as usize;
+    if packets == 0 {
+        return (0, 0);
+    }
+
+    let burst = max_burst_size as usize + 1;
+    let tbc = (packets + burst - 1) / burst - 1;
+    let tlbpc = match packets % burst {
+        0 => burst - 1,
+        residue => residue - 1,
+    };
+
+    (tbc as u8, tlbpc as u8)
+}
+
+/// Split buffer segments into pieces a TRB can describe, which must not cross a 64K boundary (xHCI 4.11.7.1)
+fn split_segments(segments: &[(u64, usize)]) -> Vec<(u64, usize)> {
+    let mut pieces = Vec::new();
+    for &(mut phys, mut len) in segments {
+        while len > 0 {
+            let boundary = MAX_TRB_TRANSFER - (phys as usize & (MAX_TRB_TRANSFER - 1));
+            let piece = if len < boundary { len } else { boundary };
+            pieces.push((phys, piece));
+            phys += piece as u64;
+            len -= piece;
+        }
+    }
+    pieces
+}
+
+/// A TD that was turned into No Op TRBs by `TransferRing::cancel_td`
+pub struct CancelledTd {
+    /// Whether the TD started at the controller's dequeue pointer, which must then be moved past it
+    pub at_dequeue: bool,
+    /// Address of the TRB after the TD, and the cycle state the controller expects there
+    pub next: u64,
+    pub next_cycle: bool,
+}
+
+pub struct TransferRing {
+    pub ring: Ring,
+    /// Physical address of the oldest TRB the controller has not reported as completed
+    dequeue: u64,
+    /// Physical address of the Event Data TRB ending each queued TD, keyed by its cookie
+    event_data: BTreeMap<u64, u64>,
+}
+
+impl TransferRing {
+    pub fn new() -> Result<TransferRing, XhciError> {
+        let ring = Ring::new(true)?;
+        let dequeue = ring.trbs.physical() as u64;
+        Ok(TransferRing {
+            ring: ring,
+            dequeue: dequeue,
+            event_data: BTreeMap::new(),
+        })
+    }
+
+    /// Value of the TR Dequeue Pointer field of an endpoint context using this ring
+    pub fn register(&self) -> u64 {
+        self.ring.register()
+    }
+
+    /// Record the completion of the TRB at `trb`, as reported by a Transfer Event, freeing it and every TRB before it
+    pub fn set_dequeue_ptr(&mut self, trb: u64) {
+        self.dequeue = trb + mem::size_of::<Trb>() as u64;
+    }
+
+    /// Record that the controller's dequeue pointer was moved to `ptr` by a Set TR Dequeue Pointer Command
+    pub fn move_dequeue_ptr(&mut self, ptr: u64) {
+        self.dequeue = ptr & !0xF;
+
+        // TDs the dequeue pointer was moved past will not report their Event Data TRB
+        let usable = self.ring.trbs.len() - 1;
+        let dequeue = self.position(self.dequeue, false).0;
+        let used = self.used_slots();
+        let skipped: Vec<u64> = self.event_data.iter().filter(|&(_, &trb)| {
+            (self.position(trb, false).0 + usable - dequeue) % usable >= used
+        }).map(|(&cookie, _)| cookie).collect();
+        for cookie in skipped {
+            self.event_data.remove(&cookie);
+        }
+    }
+
+    /// Record the completion of the TD ending in the Event Data TRB with `cookie`, whose Transfer Event does not
+    /// point at a TRB
+    pub fn complete_event_data(&mut self, cookie: u64) {
+        if let Some(trb) = self.event_data.remove(&cookie) {
+            self.set_dequeue_ptr(trb);
+        }
+    }
+
+    /// Whether `ptr` is the address of a TRB of this ring
+    pub fn contains(&self, ptr: u64) -> bool {
+        let base = self.ring.trbs.physical() as u64;
+        ptr >= base && ptr < base + mem::size_of::<[Trb; 256]>() as u64
+    }
+
+    /// Number of TRBs queued that the controller has not completed yet
+    pub fn used_slots(&self) -> usize {
+        // The Link TRB takes one entry of the ring
+        let usable = self.ring.trbs.len() - 1;
+        let enqueue = if self.ring.i >= usable { 0 } else { self.ring.i };
+        (enqueue + usable - self.position(self.dequeue, false).0) % usable
+    }
+
+    /// Number of TRBs that can be queued before the ring is full, one entry staying empty so that a full ring can be
+    /// told apart from an empty one
+    pub fn free_slots(&self) -> usize {
+        self.ring.trbs.len() - 2 - self.used_slots()
+    }
+
+    /// Check that `trbs` TRBs can be queued without overwriting TRBs the controller has not completed
+    fn reserve(&self, trbs: usize) -> Result<(), XhciError> {
+        if trbs > self.free_slots() {
+            Err(XhciError::RingFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Index of the TRB at `ptr`, with the cycle state toggled if it is the Link TRB, which the controller follows
+    /// back to the start of the ring
+    fn position(&self, ptr: u64, cycle: bool) -> (usize, bool) {
+        let base = self.ring.trbs.physical() as u64;
+        let i = (ptr.wrapping_sub(base) / mem::size_of::<Trb>() as u64) as usize;
+        if i >= self.ring.trbs.len() - 1 {
+            (0, !cycle)
+        } else {
+            (i, cycle)
+        }
+    }
+
+    /// Find the TD ending in an Event Data TRB with `cookie` among the TRBs the controller has not consumed,
+    /// starting at its dequeue pointer `dequeue` with cycle state `dcs`, and turn it into No Op TRBs
+    ///
+    /// The endpoint must be stopped.
+    pub fn cancel_td(&mut self, dequeue: u64, dcs: bool, cookie: u64) -> Option<CancelledTd> {
+        let link = self.ring.trbs.len() - 1;
+        let enqueue = if self.ring.i >= link { 0 } else { self.ring.i };
+
+        let (first, mut cycle) = self.position(dequeue, dcs);
+        let mut i = first;
+        let mut start = first;
+        while i != enqueue {
+            let (chain, found) = {
+                let trb = &self.ring.trbs[i];
+                (trb.control.read() & (1 << 4) != 0, trb.trb_type() == TrbType::EventData as u8 && trb.data.read() == cookie)
+            };
+
+            i += 1;
+            if i >= link {
+                i = 0;
+                cycle = !cycle;
+            }
+
+            if found {
+                // TRBs keep the cycle state they were written with, so the controller still owns them
+                let mut j = start;
+                while j != i {
+                    let (chain, trb_cycle) = {
+                        let trb = &self.ring.trbs[j];
+                        (trb.control.read() & (1 << 4) != 0, trb.cycle())
+                    };
+                    self.ring.trbs[j].transfer_no_op(chain, false, trb_cycle);
+                    j += 1;
+                    if j >= link {
+                        j = 0;
+                    }
+                }
+
+                self.event_data.remove(&cookie);
+                let base = self.ring.trbs.physical() as u64;
+                return Some(CancelledTd {
+                    at_dequeue: start == first,
+                    next: base + (i * mem::size_of::<Trb>()) as u64,
+                    next_cycle: cycle,
+                });
+            }
+
+            if ! chain {
+                start = i;
+            }
+        }
+
+        None
+    }
+
+    fn submit_normal<T>(&mut self, data: &Dma<T>, len: usize, chain: bool, ioc: bool) -> Result<(), XhciError> {
+        if len > mem::size_of::<T>() {
+            return Err(XhciError::InvalidState("transfer larger than buffer"));
+        }
+        if len > MAX_TRB_TRANSFER {
+            return Err(XhciError::InvalidState("transfer larger than a TRB"));
+        }
+        self.reserve(1)?;
+
+        let (trb, cycle) = self.ring.next();
+        trb.normal(data.physical(), len as u32, chain, ioc, cycle);
+        Ok(())
+    }
+
+    /// Queue a bulk OUT transfer of the first `len` bytes of `data`, chaining it to the next TRB if `chain` is set
+    pub fn submit_bulk_out<T>(&mut self, data: &Dma<T>, len: usize, chain: bool, ioc: bool) -> Result<(), XhciError> {
+        self.submit_normal(data, len, chain, ioc)
+    }
+
+    /// Queue a bulk OUT transfer of `len` bytes at `data_phys` followed by a zero length packet, in the same TD
+    pub fn submit_bulk_out_zlp(&mut self, data_phys: u64, len: usize) -> Result<(), XhciError> {
+        let pieces = split_segments(&[(data_phys, len)]);
+        self.reserve(pieces.len() + 1)?;
+
+        for (phys, len) in pieces {
+            let (trb, cycle) = self.ring.next();
+            trb.normal(phys as usize, len as u32, true, false, cycle);
+        }
+        let (trb, cycle) = self.ring.next();
+        trb.normal(0, 0, false, true, cycle);
+        Ok(())
+    }
+
+    /// Queue an interrupt transfer of `len` bytes, which the controller services at the endpoint's interval
+    pub fn submit_interrupt<T>(&mut self, data: &Dma<T>, len: usize) -> Result<(), XhciError> {
+        self.submit_normal(data, len, false, true)
+    }
+
+    /// Queue an isochronous transfer, with burst counts computed from the endpoint's max packet and burst sizes
+    pub fn submit_isoch<T>(&mut self, data: &Dma<T>, len: usize, max_packet_size: u16, max_burst_size: u8, frame_id: Option<u16>) -> Result<(), XhciError> {
+        if len > mem::size_of::<T>() {
+            return Err(XhciError::InvalidState("transfer larger than buffer"));
+        }
+        if len > MAX_TRB_TRANSFER {
+            return Err(XhciError::InvalidState("transfer larger than a TRB"));
+        }
+        if max_packet_size == 0 {
+            return Err(XhciError::InvalidState("endpoint has no max packet size"));
+        }
+        // Isochronous rings are kept short of full, so that the Link TRB can always be handed over
+        if self.free_slots() < 2 {
+            return Err(XhciError::RingFull);
+        }
+
+        let (tbc, tlbpc) = burst_counts(len, max_packet_size, max_burst_size);
+
+        let (trb, cycle) = self.ring.next();
+        trb.isoch(data.physical(), len as u32, tbc, tlbpc, frame_id, cycle);
+        Ok(())
+    }
+
+    /// Queue one transfer spanning the physical buffer segments `segments`, as a TD of chained Normal TRBs
+    pub fn submit_scatter_gather(&mut self, segments: &[(u64, usize)], ioc: bool) -> Result<(), XhciError> {
+        let pieces = split_segments(segments);
+        if pieces.is_empty() {
+            return Err(XhciError::InvalidState("transfer has no data"));
+        }
+        self.reserve(pieces.len())?;
+
+        let last = pieces.len() - 1;
+        for (i, &(phys, len)) in pieces.iter().enumerate() {
+            let (trb, cycle) = self.ring.next();
+            trb.normal(phys as usize, len as u32, i != last, ioc && i == last, cycle);
+        }
+        Ok(())
+    }
+
+    /// Queue one transfer spanning `segments` followed by an Event Data TRB, so that its Transfer Event carries
+    /// `cookie` and the number of bytes transferred by the whole TD
+    pub fn submit_with_event_data(&mut self, segments: &[(u64, usize)], cookie: u64) -> Result<(), XhciError> {
+        let pieces = split_segments(segments);
+        if pieces.is_empty() {
+            return Err(XhciError::InvalidState("transfer has no data"));
+        }
+        self.reserve(pieces.len() + 1)?;
+
+        for &(phys, len) in pieces.iter() {
+            let (trb, cycle) = self.ring.next();
+            trb.normal(phys as usize, len as u32, true, false, cycle);
+        }
+        {
+            let (trb, cycle) = self.ring.next();
+            trb.event_data(cookie, true, cycle);
+        }
+
+        // The Link TRB is handed over before a TRB is written, so the one just written is right before the enqueue pointer
+        let phys = self.ring.trbs.physical() + (self.ring.i - 1) * mem::size_of::<Trb>();
+        self.event_data.insert(cookie, phys as u64);
+        Ok(())
+    }
+
+    /// Queue a bulk IN transfer of up to `len` bytes into `data`, chaining it to the next TRB if `chain` is set
+    pub fn submit_bulk_in<T>(&mut self, data: &mut Dma<T>, len: usize, chain: bool, ioc: bool) -> Result<(), XhciError> {
+        self.submit_normal(data, len, chain, ioc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_on_64k_boundaries() {
+        assert_eq!(split_segments(&[(0x1000, 0x200)]), vec![(0x1000, 0x200)]);
+        assert_eq!(split_segments(&[(0xF000, 0x2000)]), vec![(0xF000, 0x1000), (0x10000, 0x1000)]);
+        assert_eq!(split_segments(&[(0x20000, 0x20000), (0x5000, 0)]), vec![(0x20000, 0x10000), (0x30000, 0x10000)]);
+    }
+}
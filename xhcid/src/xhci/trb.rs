@@ -1,7 +1,9 @@
 use std::{fmt, mem};
+use std::convert::TryFrom;
 use syscall::io::{Io, Mmio};
 use usb;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum TrbType {
     Reserved,
@@ -51,8 +53,9 @@ pub enum TrbType {
     /* Reserved from 40 to 47, vendor devined from 48 to 63 */
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
-pub enum TrbCompletionCode {
+pub enum CompletionCode {
     Invalid,
     Success,
     DataBuffer,
@@ -95,6 +98,65 @@ pub enum TrbCompletionCode {
     /* 224 to 255 are vendor defined information */
 }
 
+impl TryFrom<u8> for CompletionCode {
+    type Error = u8;
+
+    /// Reserved and vendor defined codes are returned as errors
+    fn try_from(code: u8) -> Result<CompletionCode, u8> {
+        if code <= CompletionCode::SplitTransaction as u8 {
+            // Codes up to Split Transaction Error are contiguous
+            Ok(unsafe { mem::transmute(code) })
+        } else {
+            Err(code)
+        }
+    }
+}
+
+impl fmt::Display for CompletionCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            CompletionCode::Invalid => "invalid",
+            CompletionCode::Success => "success",
+            CompletionCode::DataBuffer => "data buffer error",
+            CompletionCode::BabbleDetected => "babble detected",
+            CompletionCode::UsbTransaction => "USB transaction error",
+            CompletionCode::Trb => "TRB error",
+            CompletionCode::Stall => "stall",
+            CompletionCode::Resource => "resource error",
+            CompletionCode::Bandwidth => "bandwidth error",
+            CompletionCode::NoSlotsAvailable => "no slots available",
+            CompletionCode::InvalidStreamType => "invalid stream type",
+            CompletionCode::SlotNotEnabled => "slot not enabled",
+            CompletionCode::EndpointNotEnabled => "endpoint not enabled",
+            CompletionCode::ShortPacket => "short packet",
+            CompletionCode::RingUnderrun => "ring underrun",
+            CompletionCode::RingOverrun => "ring overrun",
+            CompletionCode::VfEventRingFull => "VF event ring full",
+            CompletionCode::Parameter => "parameter error",
+            CompletionCode::BandwidthOverrun => "bandwidth overrun",
+            CompletionCode::ContextState => "context state error",
+            CompletionCode::NoPingResponse => "no ping response",
+            CompletionCode::EventRingFull => "event ring full",
+            CompletionCode::IncompatibleDevice => "incompatible device",
+            CompletionCode::MissedService => "missed service",
+            CompletionCode::CommandRingStopped => "command ring stopped",
+            CompletionCode::CommandAborted => "command aborted",
+            CompletionCode::Stopped => "stopped",
+            CompletionCode::StoppedLengthInvalid => "stopped, length invalid",
+            CompletionCode::StoppedShortPacket => "stopped, short packet",
+            CompletionCode::MaxExitLatencyTooLarge => "max exit latency too large",
+            CompletionCode::Rsv30 => "reserved",
+            CompletionCode::IsochBuffer => "isoch buffer overrun",
+            CompletionCode::EventLost => "event lost",
+            CompletionCode::Undefined => "undefined error",
+            CompletionCode::InvalidStreamId => "invalid stream ID",
+            CompletionCode::SecondaryBandwidth => "secondary bandwidth error",
+            CompletionCode::SplitTransaction => "split transaction error",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum TransferKind {
     NoData,
@@ -117,6 +179,19 @@ impl Trb {
         self.control.write(control);
     }
 
+    pub fn trb_type(&self) -> u8 {
+        ((self.control.read() >> 10) & 0x3F) as u8
+    }
+
+    /// Completion code of an event TRB, or the raw value if it is reserved or vendor defined
+    pub fn completion_code(&self) -> Result<CompletionCode, u8> {
+        CompletionCode::try_from((self.status.read() >> 24) as u8)
+    }
+
+    pub fn cycle(&self) -> bool {
+        self.control.readf(1)
+    }
+
     pub fn reserved(&mut self, cycle: bool) {
         self.set(
             0,
@@ -136,6 +211,60 @@ impl Trb {
         );
     }
 
+    pub fn normal(&mut self, buffer: usize, length: u32, chain: bool, ioc: bool, cycle: bool) {
+        self.set(
+            buffer as u64,
+            length & 0x1FFFF,
+            ((TrbType::Normal as u32) << 10) |
+            ((ioc as u32) << 5) |
+            ((chain as u32) << 4) |
+            (cycle as u32)
+        );
+    }
+
+    /// No Op transfer TRB, which the controller skips over, used to replace the TRBs of a cancelled TD
+    pub fn transfer_no_op(&mut self, chain: bool, ioc: bool, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((TrbType::NoOp as u32) << 10) |
+            ((ioc as u32) << 5) |
+            ((chain as u32) << 4) |
+            (cycle as u32)
+        );
+    }
+
+    /// Event Data TRB, which reports `data` in the Transfer Event of its TD instead of a TRB pointer
+    pub fn event_data(&mut self, data: u64, ioc: bool, cycle: bool) {
+        self.set(
+            data,
+            0,
+            ((TrbType::EventData as u32) << 10) |
+            ((ioc as u32) << 5) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn isoch(&mut self, buffer: usize, length: u32, tbc: u8, tlbpc: u8, frame_id: Option<u16>, cycle: bool) {
+        // Without a Frame ID the controller starts the transfer as soon as possible
+        let (frame_id, sia) = match frame_id {
+            Some(frame_id) => (frame_id, false),
+            None => (0, true),
+        };
+
+        self.set(
+            buffer as u64,
+            length & 0x1FFFF,
+            ((sia as u32) << 31) |
+            (((frame_id as u32) & 0x7FF) << 20) |
+            (((tlbpc as u32) & 0xF) << 16) |
+            ((TrbType::Isoch as u32) << 10) |
+            (((tbc as u32) & 0x3) << 7) |
+            (1 << 5) |
+            (cycle as u32)
+        );
+    }
+
     pub fn no_op_cmd(&mut self, cycle: bool) {
         self.set(
             0,
@@ -155,12 +284,132 @@ impl Trb {
         );
     }
 
-    pub fn address_device(&mut self, slot_id: u8, input: usize, cycle: bool) {
+    pub fn disable_slot(&mut self, slot_id: u8, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((slot_id as u32) << 24) |
+            ((TrbType::DisableSlot as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn reset_device(&mut self, slot_id: u8, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((slot_id as u32) << 24) |
+            ((TrbType::ResetDevice as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn address_device(&mut self, slot_id: u8, input: usize, bsr: bool, cycle: bool) {
         self.set(
             input as u64,
             0,
             ((slot_id as u32) << 24) |
             ((TrbType::AddressDevice as u32) << 10) |
+            ((bsr as u32) << 9) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn configure_endpoint(&mut self, slot_id: u8, input: usize, deconfigure: bool, cycle: bool) {
+        self.set(
+            input as u64,
+            0,
+            ((slot_id as u32) << 24) |
+            ((TrbType::ConfigureEndpoint as u32) << 10) |
+            ((deconfigure as u32) << 9) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn evaluate_context(&mut self, slot_id: u8, input: usize, cycle: bool) {
+        self.set(
+            input as u64,
+            0,
+            ((slot_id as u32) << 24) |
+            ((TrbType::EvaluateContext as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn reset_endpoint(&mut self, slot_id: u8, endpoint_id: u8, preserve: bool, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((slot_id as u32) << 24) |
+            (((endpoint_id as u32) & 0x1F) << 16) |
+            ((TrbType::ResetEndpoint as u32) << 10) |
+            ((preserve as u32) << 9) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn stop_endpoint(&mut self, slot_id: u8, endpoint_id: u8, suspend: bool, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((slot_id as u32) << 24) |
+            ((suspend as u32) << 23) |
+            (((endpoint_id as u32) & 0x1F) << 16) |
+            ((TrbType::StopEndpoint as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn set_tr_dequeue_pointer(&mut self, slot_id: u8, endpoint_id: u8, stream_id: u16, dequeue: u64, dcs: bool, sct: u8, cycle: bool) {
+        self.set(
+            (dequeue & !0xF) | (((sct as u64) & 0b111) << 1) | (dcs as u64),
+            (stream_id as u32) << 16,
+            ((slot_id as u32) << 24) |
+            (((endpoint_id as u32) & 0x1F) << 16) |
+            ((TrbType::SetTrDequeuePointer as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn negotiate_bandwidth(&mut self, slot_id: u8, cycle: bool) {
+        self.set(
+            0,
+            0,
+            ((slot_id as u32) << 24) |
+            ((TrbType::NegotiateBandwidth as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn set_latency_tolerance_value(&mut self, belt: u16, cycle: bool) {
+        self.set(
+            0,
+            0,
+            (((belt as u32) & 0xFFF) << 16) |
+            ((TrbType::SetLatencyToleranceValue as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn force_header(&mut self, port_id: u8, packet_type: u8, header_info: [u32; 3], cycle: bool) {
+        self.set(
+            ((header_info[1] as u64) << 32) |
+            ((header_info[0] & !0x1F) as u64) |
+            ((packet_type & 0x1F) as u64),
+            header_info[2],
+            ((port_id as u32) << 24) |
+            ((TrbType::ForceHeader as u32) << 10) |
+            (cycle as u32)
+        );
+    }
+
+    pub fn get_port_bandwidth(&mut self, context: usize, speed: u8, hub_slot: u8, cycle: bool) {
+        self.set(
+            context as u64,
+            0,
+            ((hub_slot as u32) << 24) |
+            (((speed as u32) & 0xF) << 16) |
+            ((TrbType::GetPortBandwidth as u32) << 10) |
             (cycle as u32)
         );
     }
@@ -182,6 +431,7 @@ impl Trb {
             length as u32,
             ((input as u32) << 16) |
             ((TrbType::DataStage as u32) << 10) |
+            (1 << 2) |
             (cycle as u32)
         );
     }
@@ -211,3 +461,136 @@ impl fmt::Display for Trb {
                   self.data.read(), self.status.read(), self.control.read())
     }
 }
+
+/// A TRB decoded according to its type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrbKind {
+    Normal { data_ptr: u64, trb_len: u32, td_size: u8, interrupter: u16, chain: bool, ioc: bool, idt: bool, bei: bool },
+    SetupStage { setup: u64, trb_len: u32, interrupter: u16, ioc: bool, idt: bool, transfer: u8 },
+    DataStage { data_ptr: u64, trb_len: u32, td_size: u8, interrupter: u16, chain: bool, ioc: bool, idt: bool, input: bool },
+    StatusStage { interrupter: u16, chain: bool, ioc: bool, input: bool },
+    Isoch { data_ptr: u64, trb_len: u32, td_size: u8, interrupter: u16, chain: bool, ioc: bool, idt: bool, tbc: u8, tlbpc: u8, frame_id: u16, sia: bool },
+    Link { segment_ptr: u64, interrupter: u16, toggle_cycle: bool, chain: bool, ioc: bool },
+    EventData { event_data: u64, interrupter: u16, chain: bool, ioc: bool, bei: bool },
+    NoOp { interrupter: u16, chain: bool, ioc: bool },
+    EnableSlot { slot_type: u8 },
+    DisableSlot { slot: u8 },
+    AddressDevice { input_ctx: u64, bsr: bool, slot: u8 },
+    ConfigureEndpoint { input_ctx: u64, deconfigure: bool, slot: u8 },
+    EvaluateContext { input_ctx: u64, slot: u8 },
+    ResetEndpoint { preserve: bool, endpoint_id: u8, slot: u8 },
+    StopEndpoint { endpoint_id: u8, suspend: bool, slot: u8 },
+    SetTrDequeuePointer { dequeue: u64, dcs: bool, sct: u8, stream_id: u16, endpoint_id: u8, slot: u8 },
+    ResetDevice { slot: u8 },
+    ForceEvent { event_ptr: u64, vf_interrupter: u16, vf_id: u8 },
+    NegotiateBandwidth { slot: u8 },
+    SetLatencyToleranceValue { belt: u16 },
+    GetPortBandwidth { context_ptr: u64, speed: u8, hub_slot: u8 },
+    ForceHeader { packet_type: u8, header_info: [u32; 3], port: u8 },
+    NoOpCmd,
+    Transfer { trb_ptr: u64, trb_len: u32, code: u8, event_data: bool, endpoint_id: u8, slot: u8 },
+    CommandCompletion { command_ptr: u64, parameter: u32, code: u8, vf_id: u8, slot: u8 },
+    PortStatusChange { port: u8, code: u8 },
+    BandwidthRequest { code: u8, slot: u8 },
+    Doorbell { reason: u8, code: u8, vf_id: u8, slot: u8 },
+    HostController { code: u8 },
+    DeviceNotification { notification_type: u8, data: u64, code: u8, slot: u8 },
+    MfindexWrap { code: u8 },
+    /// Types 24 to 31 and 40 to 47
+    Reserved { kind: u8 },
+    /// Types 48 to 63
+    Vendor { kind: u8, data: u64, status: u32, control: u32 },
+}
+
+impl Trb {
+    pub fn decode(&self) -> TrbKind {
+        let data = self.data.read();
+        let status = self.status.read();
+        let control = self.control.read();
+
+        let kind = ((control >> 10) & 0x3F) as u8;
+        let bit = |n: u32| control & (1 << n) != 0;
+        let trb_len = status & 0x1FFFF;
+        let td_size = ((status >> 17) & 0x1F) as u8;
+        let interrupter = (status >> 22) as u16;
+        let code = (status >> 24) as u8;
+        let slot = (control >> 24) as u8;
+        let endpoint_id = ((control >> 16) & 0x1F) as u8;
+        let vf_id = ((control >> 16) & 0xFF) as u8;
+
+        match kind {
+            1 => TrbKind::Normal {
+                data_ptr: data, trb_len: trb_len, td_size: td_size, interrupter: interrupter,
+                chain: bit(4), ioc: bit(5), idt: bit(6), bei: bit(9),
+            },
+            2 => TrbKind::SetupStage {
+                setup: data, trb_len: trb_len, interrupter: interrupter,
+                ioc: bit(5), idt: bit(6), transfer: ((control >> 16) & 0b11) as u8,
+            },
+            3 => TrbKind::DataStage {
+                data_ptr: data, trb_len: trb_len, td_size: td_size, interrupter: interrupter,
+                chain: bit(4), ioc: bit(5), idt: bit(6), input: bit(16),
+            },
+            4 => TrbKind::StatusStage {
+                interrupter: interrupter, chain: bit(4), ioc: bit(5), input: bit(16),
+            },
+            5 => TrbKind::Isoch {
+                data_ptr: data, trb_len: trb_len, td_size: td_size, interrupter: interrupter,
+                chain: bit(4), ioc: bit(5), idt: bit(6),
+                tbc: ((control >> 7) & 0b11) as u8, tlbpc: ((control >> 16) & 0xF) as u8,
+                frame_id: ((control >> 20) & 0x7FF) as u16, sia: bit(31),
+            },
+            6 => TrbKind::Link {
+                segment_ptr: data, interrupter: interrupter, toggle_cycle: bit(1), chain: bit(4), ioc: bit(5),
+            },
+            7 => TrbKind::EventData {
+                event_data: data, interrupter: interrupter, chain: bit(4), ioc: bit(5), bei: bit(9),
+            },
+            8 => TrbKind::NoOp { interrupter: interrupter, chain: bit(4), ioc: bit(5) },
+            9 => TrbKind::EnableSlot { slot_type: ((control >> 16) & 0x1F) as u8 },
+            10 => TrbKind::DisableSlot { slot: slot },
+            11 => TrbKind::AddressDevice { input_ctx: data & !0xF, bsr: bit(9), slot: slot },
+            12 => TrbKind::ConfigureEndpoint { input_ctx: data & !0xF, deconfigure: bit(9), slot: slot },
+            13 => TrbKind::EvaluateContext { input_ctx: data & !0xF, slot: slot },
+            14 => TrbKind::ResetEndpoint { preserve: bit(9), endpoint_id: endpoint_id, slot: slot },
+            15 => TrbKind::StopEndpoint { endpoint_id: endpoint_id, suspend: bit(23), slot: slot },
+            16 => TrbKind::SetTrDequeuePointer {
+                dequeue: data & !0xF, dcs: data & 1 == 1, sct: ((data >> 1) & 0b111) as u8,
+                stream_id: (status >> 16) as u16, endpoint_id: endpoint_id, slot: slot,
+            },
+            17 => TrbKind::ResetDevice { slot: slot },
+            18 => TrbKind::ForceEvent { event_ptr: data & !0xF, vf_interrupter: interrupter, vf_id: vf_id },
+            19 => TrbKind::NegotiateBandwidth { slot: slot },
+            20 => TrbKind::SetLatencyToleranceValue { belt: ((control >> 16) & 0xFFF) as u16 },
+            21 => TrbKind::GetPortBandwidth { context_ptr: data & !0xF, speed: ((control >> 16) & 0xF) as u8, hub_slot: slot },
+            22 => TrbKind::ForceHeader {
+                packet_type: (data & 0x1F) as u8,
+                header_info: [(data as u32) & !0x1F, (data >> 32) as u32, status],
+                port: slot,
+            },
+            23 => TrbKind::NoOpCmd,
+            32 => TrbKind::Transfer {
+                trb_ptr: data, trb_len: status & 0xFFFFFF, code: code, event_data: bit(2), endpoint_id: endpoint_id, slot: slot,
+            },
+            33 => TrbKind::CommandCompletion {
+                command_ptr: data & !0xF, parameter: status & 0xFFFFFF, code: code, vf_id: vf_id, slot: slot,
+            },
+            34 => TrbKind::PortStatusChange { port: (data >> 24) as u8, code: code },
+            35 => TrbKind::BandwidthRequest { code: code, slot: slot },
+            36 => TrbKind::Doorbell { reason: (data & 0x1F) as u8, code: code, vf_id: vf_id, slot: slot },
+            37 => TrbKind::HostController { code: code },
+            38 => TrbKind::DeviceNotification {
+                notification_type: ((data >> 4) & 0xF) as u8, data: data >> 8, code: code, slot: slot,
+            },
+            39 => TrbKind::MfindexWrap { code: code },
+            48 ... 63 => TrbKind::Vendor { kind: kind, data: data, status: status, control: control },
+            _ => TrbKind::Reserved { kind: kind },
+        }
+    }
+}
+
+impl fmt::Display for TrbKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}